@@ -0,0 +1,19 @@
+// Wires up the buttons on the share page, which otherwise fall back to a plain link.
+document.addEventListener("DOMContentLoaded", () => {
+    const copy = document.getElementById("copy-link");
+    const share = document.getElementById("share-to");
+    const url = copy.dataset.url;
+
+    copy.hidden = false;
+    copy.addEventListener("click", async () => {
+        await navigator.clipboard.writeText(url);
+        copy.textContent = "Copied!";
+    });
+
+    if (navigator.share) {
+        share.addEventListener("click", (event) => {
+            event.preventDefault();
+            navigator.share({ title: share.dataset.title, url });
+        });
+    }
+});
@@ -0,0 +1,10 @@
+use vergen::EmitBuilder;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Embed the build time and git commit so a running server can report what it was built from.
+    EmitBuilder::builder()
+        .build_timestamp()
+        .git_sha(false)
+        .emit()?;
+    Ok(())
+}
@@ -0,0 +1,75 @@
+//! Optional access log for embed requests, written in the Apache Combined Log Format for operators
+//! with tooling built around it.
+
+use std::{
+    io,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use log::warn;
+use tokio::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+
+/// An append only log file that gets rotated to `{path}.1` once it grows past a size limit,
+/// replacing whatever was rotated out last time.
+#[derive(Clone)]
+pub struct AccessLog {
+    path: PathBuf,
+    max_bytes: u64,
+    file: Arc<Mutex<File>>,
+}
+
+impl AccessLog {
+    /// Opens the log file for appending, creating it if it doesn't exist yet.
+    pub async fn open(path: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+        let path = path.into();
+        let file = open_append(&path).await?;
+        Ok(AccessLog {
+            path,
+            max_bytes,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Writes a line to the log, rotating the file first if the line would take it over the size
+    /// limit. Failures are logged rather than returned, a broken access log shouldn't take the
+    /// request down with it.
+    pub async fn write(&self, line: &str) {
+        if let Err(err) = self.try_write(line).await {
+            warn!(
+                "Failed to write to access log {}: {err}",
+                self.path.display()
+            );
+        }
+    }
+
+    async fn try_write(&self, line: &str) -> io::Result<()> {
+        let mut file = self.file.lock().await;
+
+        let size = file.metadata().await?.len();
+        if size > 0 && size + line.len() as u64 + 1 > self.max_bytes {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            tokio::fs::rename(&self.path, rotated).await?;
+            *file = open_append(&self.path).await?;
+        }
+
+        file.write_all(format!("{line}\n").as_bytes()).await
+    }
+}
+
+/// Opens a file for appending, creating it if needed.
+async fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+}
@@ -0,0 +1,553 @@
+//! JSON response bodies returned by the machine readable endpoints, for people building their own
+//! embed renderers on top of vxsky.
+
+use atrium_api::app::bsky::{
+    actor::defs::ProfileViewBasic,
+    feed::{
+        defs::PostView,
+        post,
+    },
+};
+use axum::{
+    http::StatusCode,
+    response::{
+        IntoResponse,
+        Response,
+    },
+    Json,
+};
+use percent_encoding::{
+    utf8_percent_encode,
+    NON_ALPHANUMERIC,
+};
+use serde::Serialize;
+
+use crate::embed::post_images;
+
+/// The structured data used to build an embed card, without any of the HTML around it.
+#[derive(Debug, Serialize)]
+pub struct EmbedCard {
+    /// The title of the card, the author's display name and handle.
+    pub title: String,
+    /// The text content of the post.
+    pub description: String,
+    /// A link to the combined thumbnail for the post, if it has any images.
+    pub image_url: Option<String>,
+    /// The author of the post.
+    pub author: EmbedCardAuthor,
+    /// When the post was created, as reported by the post's record.
+    pub created_at: String,
+    /// How many times this post's embed has been viewed, if view counting is enabled.
+    pub view_count: Option<u64>,
+}
+
+/// The author details included in an [EmbedCard].
+#[derive(Debug, Serialize)]
+pub struct EmbedCardAuthor {
+    pub did: String,
+    pub handle: String,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
+}
+
+impl EmbedCard {
+    pub fn new(view: &PostView, record: &post::Record, base_url: &str) -> Self {
+        let image_url = post_images(view).map(|_| combined_image_url(base_url, &view.uri));
+
+        EmbedCard {
+            title: embed_title(&view.author),
+            description: record.text.to_owned(),
+            image_url,
+            author: EmbedCardAuthor::from(&view.author),
+            created_at: record.created_at.to_owned(),
+            view_count: None,
+        }
+    }
+}
+
+impl From<&ProfileViewBasic> for EmbedCardAuthor {
+    fn from(profile: &ProfileViewBasic) -> Self {
+        EmbedCardAuthor {
+            did: profile.did.to_owned(),
+            handle: profile.handle.to_owned(),
+            display_name: profile.display_name.to_owned(),
+            avatar: profile.avatar.to_owned(),
+        }
+    }
+}
+
+/// The JSON response returned to Iframely when it asks for `?format=json`, following the embed.ly
+/// style format Iframely uses for its own API.
+#[derive(Debug, Serialize)]
+pub struct IframelyResponse {
+    /// The canonical URL of the post on bluesky.
+    pub url: String,
+    pub meta: IframelyMeta,
+    /// The media that can be used to build the embed, like the combined thumbnail.
+    pub links: Vec<IframelyLink>,
+    /// The kind of embed this response describes.
+    pub rel: Vec<&'static str>,
+}
+
+/// Metadata describing the post in an [IframelyResponse].
+#[derive(Debug, Serialize)]
+pub struct IframelyMeta {
+    pub title: String,
+    pub description: String,
+    pub author: String,
+    pub author_url: String,
+    pub canonical: String,
+    pub site: &'static str,
+    pub date: String,
+}
+
+/// A single piece of media in an [IframelyResponse].
+#[derive(Debug, Serialize)]
+pub struct IframelyLink {
+    pub href: String,
+    /// What the media is used for, like `thumbnail` or `icon`.
+    pub rel: Vec<&'static str>,
+    /// The MIME type of the media.
+    #[serde(rename = "type")]
+    pub media_type: &'static str,
+}
+
+impl IframelyResponse {
+    /// Builds the response for a post, `thumbnail_type` being the MIME type combined thumbnails are
+    /// encoded with.
+    pub fn new(
+        view: &PostView,
+        record: &post::Record,
+        post_url: String,
+        base_url: &str,
+        thumbnail_type: &'static str,
+    ) -> Self {
+        let (thumbnail, media_type) = match post_images(view) {
+            Some(_) => (combined_image_url(base_url, &view.uri), thumbnail_type),
+            None => (format!("{base_url}/text-only.png"), "image/png"),
+        };
+
+        let mut links = vec![IframelyLink {
+            href: thumbnail,
+            rel: vec!["thumbnail", "ssl"],
+            media_type,
+        }];
+
+        if let Some(avatar) = &view.author.avatar {
+            links.push(IframelyLink {
+                href: avatar.to_owned(),
+                rel: vec!["icon", "ssl"],
+                media_type: "image/jpeg",
+            });
+        }
+
+        IframelyResponse {
+            url: post_url.to_owned(),
+            meta: IframelyMeta {
+                title: embed_title(&view.author),
+                description: record.text.to_owned(),
+                author: view
+                    .author
+                    .display_name
+                    .to_owned()
+                    .filter(|display_name| !display_name.is_empty())
+                    .unwrap_or_else(|| view.author.handle.to_owned()),
+                author_url: format!("https://bsky.app/profile/{}", view.author.handle),
+                canonical: post_url,
+                site: "Bluesky",
+                date: record.created_at.to_owned(),
+            },
+            links,
+            rel: vec!["summary_card"],
+        }
+    }
+}
+
+/// A Slack message attachment for a post, returned by the `/card` endpoint for Slack apps to pass
+/// along as the unfurl for a link.
+#[derive(Debug, Serialize)]
+pub struct SlackAttachment {
+    pub title: String,
+    pub title_link: String,
+    pub text: String,
+    /// A link to the combined thumbnail for the post, if it has any images.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    pub footer: &'static str,
+    /// When the post was created, as a Unix timestamp.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ts: Option<i64>,
+}
+
+impl SlackAttachment {
+    pub fn new(view: &PostView, record: &post::Record, post_url: String, base_url: &str) -> Self {
+        SlackAttachment {
+            title: embed_title(&view.author),
+            title_link: post_url,
+            text: record.text.to_owned(),
+            image_url: post_images(view).map(|_| combined_image_url(base_url, &view.uri)),
+            footer: "Bluesky",
+            ts: chrono::DateTime::parse_from_rfc3339(&record.created_at)
+                .map(|created_at| created_at.timestamp())
+                .ok(),
+        }
+    }
+}
+
+/// How long consumers can cache an oEmbed response for, in seconds.
+const OEMBED_CACHE_AGE: u32 = 3600;
+
+/// An oEmbed response for a post, returned by `/oembed.json`. Posts with images are a `photo` of
+/// the combined thumbnail, other posts are a plain `link`.
+#[derive(Debug, Serialize)]
+pub struct OEmbedResponse {
+    #[serde(rename = "type")]
+    pub oembed_type: &'static str,
+    pub version: &'static str,
+    pub title: String,
+    pub author_name: String,
+    pub author_url: String,
+    pub provider_name: &'static str,
+    pub provider_url: String,
+    pub cache_age: u32,
+    /// The combined thumbnail, only set for `photo` responses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail_height: Option<u32>,
+}
+
+impl OEmbedResponse {
+    /// Builds the response for a post, `thumbnail` being the dimensions of its combined thumbnail
+    /// if it has any images.
+    pub fn new(view: &PostView, base_url: &str, thumbnail: Option<(u32, u32)>) -> Self {
+        let (width, height) = thumbnail.unzip();
+        let image_url = thumbnail.map(|_| combined_image_url(base_url, &view.uri));
+
+        OEmbedResponse {
+            oembed_type: match thumbnail {
+                Some(_) => "photo",
+                None => "link",
+            },
+            version: "1.0",
+            title: embed_title(&view.author),
+            author_name: view.author.handle.to_owned(),
+            author_url: format!("https://bsky.app/profile/{}", view.author.handle),
+            provider_name: "vxsky",
+            provider_url: base_url.to_owned(),
+            cache_age: OEMBED_CACHE_AGE,
+            url: image_url.to_owned(),
+            width,
+            height,
+            thumbnail_url: image_url,
+            thumbnail_width: width,
+            thumbnail_height: height,
+        }
+    }
+}
+
+/// The longest headline search engines will show for an article, anything longer gets cut off.
+const JSON_LD_HEADLINE_LENGTH: usize = 110;
+
+/// A schema.org `Article` describing a post as JSON-LD structured data, returned by the `/json-ld`
+/// endpoint and included in the image embed page for search engines to pick up.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JsonLdArticle {
+    #[serde(rename = "@context")]
+    pub context: &'static str,
+    #[serde(rename = "@type")]
+    pub schema_type: &'static str,
+    /// The start of the post's text, cut off at [JSON_LD_HEADLINE_LENGTH] characters, or the
+    /// content warning instead if the post has one.
+    pub headline: String,
+    pub author: JsonLdPerson,
+    pub date_published: String,
+    /// A link to the combined thumbnail for the post, if it has any images.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    pub url: String,
+}
+
+/// The author of a [JsonLdArticle].
+#[derive(Debug, Serialize)]
+pub struct JsonLdPerson {
+    #[serde(rename = "@type")]
+    pub schema_type: &'static str,
+    pub name: String,
+    pub url: String,
+}
+
+impl JsonLdArticle {
+    pub fn new(
+        author: &ProfileViewBasic,
+        record: &post::Record,
+        content_warning: &Option<String>,
+        post_url: String,
+        image: Option<String>,
+    ) -> Self {
+        let headline = match content_warning {
+            Some(label) => format!("Content Warning ({label})"),
+            None => record.text.chars().take(JSON_LD_HEADLINE_LENGTH).collect(),
+        };
+
+        JsonLdArticle {
+            context: "https://schema.org",
+            schema_type: "Article",
+            headline,
+            author: JsonLdPerson {
+                schema_type: "Person",
+                name: embed_title(author),
+                url: format!("https://bsky.app/profile/{}", author.handle),
+            },
+            date_published: record.created_at.to_owned(),
+            image,
+            url: post_url,
+        }
+    }
+
+    /// Serializes the article for use inside a `<script>` tag, escaping any `</` so text in the
+    /// post can't close the tag early.
+    pub fn to_script(&self) -> String {
+        serde_json::to_string(self)
+            .unwrap_or_default()
+            .replace("</", "<\\/")
+    }
+}
+
+/// The NodeInfo schema version served by this application.
+pub const NODEINFO_SCHEMA: &str = "http://nodeinfo.diaspora.software/ns/schema/2.0";
+
+/// The discovery document served at `/.well-known/nodeinfo`, pointing at the actual NodeInfo.
+#[derive(Debug, Serialize)]
+pub struct NodeInfoLinks {
+    pub links: Vec<NodeInfoLink>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeInfoLink {
+    pub rel: &'static str,
+    pub href: String,
+}
+
+/// A minimal NodeInfo 2.0 document describing this application, so Fediverse tools probing it
+/// know what kind of service it is.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfo {
+    pub version: &'static str,
+    pub software: NodeInfoSoftware,
+    /// vxsky doesn't federate with anything, so this is always empty.
+    pub protocols: Vec<&'static str>,
+    pub services: NodeInfoServices,
+    pub open_registrations: bool,
+    pub usage: NodeInfoUsage,
+    pub metadata: NodeInfoMetadata,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeInfoSoftware {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeInfoServices {
+    pub inbound: Vec<&'static str>,
+    pub outbound: Vec<&'static str>,
+}
+
+/// Usage statistics, vxsky has no users of its own so there's nothing to count.
+#[derive(Debug, Serialize)]
+pub struct NodeInfoUsage {
+    pub users: NodeInfoUsers,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NodeInfoUsers {}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeInfoMetadata {
+    pub node_name: &'static str,
+    pub node_description: &'static str,
+}
+
+impl NodeInfo {
+    pub fn new() -> Self {
+        NodeInfo {
+            version: "2.0",
+            software: NodeInfoSoftware {
+                name: env!("CARGO_PKG_NAME"),
+                version: env!("CARGO_PKG_VERSION"),
+            },
+            protocols: Vec::new(),
+            services: NodeInfoServices {
+                inbound: Vec::new(),
+                outbound: Vec::new(),
+            },
+            open_registrations: false,
+            usage: NodeInfoUsage {
+                users: NodeInfoUsers {},
+            },
+            metadata: NodeInfoMetadata {
+                node_name: "vxsky",
+                node_description: "A metadata proxy that improves embeds for bluesky posts",
+            },
+        }
+    }
+}
+
+/// Information about the build of vxsky that is running.
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    /// The git commit the build was made from.
+    pub git_sha: &'static str,
+    pub build_timestamp: &'static str,
+}
+
+impl VersionInfo {
+    pub const CURRENT: VersionInfo = VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("VERGEN_GIT_SHA"),
+        build_timestamp: env!("VERGEN_BUILD_TIMESTAMP"),
+    };
+}
+
+/// The DID and handle an identifier resolves to, returned by `/api/v1/resolve/:identifier`.
+#[derive(Debug, Serialize)]
+pub struct ResolvedIdentity {
+    pub did: String,
+    pub handle: String,
+}
+
+/// A summary of how the server has been doing since it started, returned by `/status`.
+#[derive(Debug, Serialize)]
+pub struct StatusInfo {
+    pub requests_served: u64,
+    /// Estimated request latency percentiles in milliseconds, `None` until a request has been
+    /// served.
+    pub latency_p50_ms: Option<u64>,
+    pub latency_p90_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+}
+
+/// The engagement counts of a post, pushed to clients watching the post over a WebSocket whenever
+/// they change.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct PostCounts {
+    pub like_count: i32,
+    pub repost_count: i32,
+    pub reply_count: i32,
+}
+
+impl From<&PostView> for PostCounts {
+    fn from(view: &PostView) -> Self {
+        PostCounts {
+            like_count: view.like_count.unwrap_or_default(),
+            repost_count: view.repost_count.unwrap_or_default(),
+            reply_count: view.reply_count.unwrap_or_default(),
+        }
+    }
+}
+
+/// How a user agent was classified, returned by `/diagnostics/bot-detection` for people checking
+/// whether their service will be sent an embed page.
+#[derive(Debug, Serialize)]
+pub struct BotDetection {
+    pub is_embed_bot: bool,
+    pub ua: String,
+    /// The rule that classified the user agent as an embed bot, if any did.
+    pub matched_rule: Option<&'static str>,
+}
+
+/// The JSON body returned when a request fails and the client asked for JSON.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    /// A human readable description of what went wrong.
+    pub error: String,
+    /// The HTTP status code of the response.
+    pub code: u16,
+}
+
+/// The JSON error returned by the machine readable endpoints, so API clients always get JSON back
+/// rather than the plain text or HTML errors the embed pages use.
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    /// The HTTP status code of the response.
+    pub code: u16,
+    /// A human readable description of what went wrong.
+    pub message: String,
+    pub kind: ErrorKind,
+}
+
+/// The broad category of an [ApiError], for clients that want to handle errors without matching
+/// on status codes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    InvalidRequest,
+    Unauthorized,
+    NotFound,
+    Unprocessable,
+    NotImplemented,
+    Internal,
+}
+
+impl From<StatusCode> for ErrorKind {
+    fn from(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::Unauthorized,
+            StatusCode::NOT_FOUND | StatusCode::GONE => ErrorKind::NotFound,
+            StatusCode::UNPROCESSABLE_ENTITY => ErrorKind::Unprocessable,
+            StatusCode::NOT_IMPLEMENTED => ErrorKind::NotImplemented,
+            status if status.is_client_error() => ErrorKind::InvalidRequest,
+            _ => ErrorKind::Internal,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        // JSON errors skip the error handling middleware, so they get reported to Sentry here.
+        if status.is_server_error() {
+            sentry::capture_message(&self.message, sentry::Level::Error);
+        }
+
+        (status, Json(self)).into_response()
+    }
+}
+
+/// Builds the title used for embed cards, matching what the HTML templates show. Falls back to
+/// just the handle if the author has no display name set.
+pub fn embed_title(profile: &ProfileViewBasic) -> String {
+    match profile.display_name.as_deref() {
+        Some(display_name) if !display_name.is_empty() => {
+            format!("{display_name} (@{})", profile.handle)
+        }
+        _ => format!("@{}", profile.handle),
+    }
+}
+
+/// Builds the URL of the oEmbed endpoint for a post, used for oEmbed discovery.
+pub fn oembed_url(base_url: &str, post_url: &str) -> String {
+    let url = utf8_percent_encode(post_url, NON_ALPHANUMERIC);
+    format!("{base_url}/oembed.json?url={url}")
+}
+
+/// Builds the URL of the combined thumbnail rendering endpoint for a post's ATUri.
+pub fn combined_image_url(base_url: &str, aturi: &str) -> String {
+    let uri = utf8_percent_encode(aturi, NON_ALPHANUMERIC);
+    format!("{base_url}/render-combined-image.png?uri={uri}")
+}
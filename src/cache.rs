@@ -0,0 +1,128 @@
+//! In-memory caches for combined thumbnails and rendered embed pages, so popular posts don't get
+//! looked up and rendered again every time another service embeds them.
+
+use std::{
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use axum::body::Bytes;
+use dashmap::DashMap;
+use lru::LruCache;
+use tokio::sync::Mutex;
+
+use crate::processing::CombinedThumbnail;
+
+/// How many thumbnails are kept in the cache if no capacity is configured.
+pub const DEFAULT_CAPACITY: NonZeroUsize = match NonZeroUsize::new(256) {
+    Some(capacity) => capacity,
+    None => unreachable!(),
+};
+
+/// A least recently used cache of combined thumbnails keyed by the post's ATUri and the blur
+/// strength they were rendered with, as `{aturi}#{blur}`. Once the cache is full, adding a new
+/// thumbnail evicts the one that was used longest ago.
+#[derive(Clone)]
+pub struct ImageCache {
+    entries: Arc<Mutex<LruCache<String, CombinedThumbnail>>>,
+}
+
+impl ImageCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        ImageCache {
+            entries: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    /// Gets the cached thumbnail for a key built by `render_thumbnail`, marking it as recently
+    /// used.
+    pub async fn get(&self, key: &str) -> Option<CombinedThumbnail> {
+        self.entries.lock().await.get(key).cloned()
+    }
+
+    pub async fn insert(&self, key: String, thumbnail: CombinedThumbnail) {
+        self.entries.lock().await.put(key, thumbnail);
+    }
+
+    /// How many thumbnails are currently cached.
+    pub async fn len(&self) -> usize {
+        self.entries.lock().await.len()
+    }
+}
+
+impl Default for ImageCache {
+    fn default() -> Self {
+        ImageCache::new(DEFAULT_CAPACITY)
+    }
+}
+
+/// How long rendered embed pages are cached for if no TTL is configured.
+pub const DEFAULT_TEMPLATE_TTL: Duration = Duration::from_secs(300);
+
+/// A cache of rendered embed pages, keyed by the post's ATUri and whatever else changes how the
+/// page is rendered. Entries expire after a fixed time, so edits to a post or its author's profile
+/// show up eventually and view counts don't stay stale for too long.
+#[derive(Clone)]
+pub struct TemplateCache {
+    entries: Arc<DashMap<String, (Instant, Bytes)>>,
+    ttl: Duration,
+}
+
+impl TemplateCache {
+    /// Creates a cache where entries live for `ttl`, a TTL of zero disables caching.
+    pub fn new(ttl: Duration) -> Self {
+        TemplateCache {
+            entries: Arc::new(DashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Gets a rendered page if it's in the cache and hasn't expired yet.
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let entry = self.entries.get(key)?;
+        let (rendered_at, html) = entry.value();
+        match rendered_at.elapsed() < self.ttl {
+            true => Some(html.clone()),
+            false => {
+                drop(entry);
+                // Another request may have rendered the page again in the meantime.
+                self.entries
+                    .remove_if(key, |_, (rendered_at, _)| rendered_at.elapsed() >= self.ttl);
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, key: String, html: Bytes) {
+        if !self.ttl.is_zero() {
+            self.entries.insert(key, (Instant::now(), html));
+        }
+    }
+
+    /// Removes every expired entry, since pages for posts that stop being requested would
+    /// otherwise stick around forever.
+    pub fn purge_expired(&self) {
+        self.entries
+            .retain(|_, (rendered_at, _)| rendered_at.elapsed() < self.ttl);
+    }
+
+    /// How many pages are currently cached, including expired ones that haven't been removed yet.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// How long entries live in the cache for.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+}
+
+impl Default for TemplateCache {
+    fn default() -> Self {
+        TemplateCache::new(DEFAULT_TEMPLATE_TTL)
+    }
+}
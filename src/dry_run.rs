@@ -0,0 +1,48 @@
+//! Stub data served in dry-run mode, where no requests are made to the bluesky API at all so the
+//! routing and template rendering can be load tested on their own.
+
+use atrium_api::app::bsky::{
+    actor::defs::ProfileViewBasic,
+    feed::post,
+};
+
+use crate::templates::TextEmbed;
+
+/// The text used for every stub post.
+const STUB_TEXT: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod \
+                         tempor incididunt ut labore et dolore magna aliqua.";
+
+/// Builds a stub embed page for a post with fake data, using the text only placeholder image so
+/// no thumbnails need to be rendered either.
+pub fn stub_embed(base_url: &str, post_url: String, matrix_preview: bool) -> TextEmbed {
+    let profile = ProfileViewBasic {
+        avatar: None,
+        did: "did:plc:vxskydryrun".to_owned(),
+        display_name: Some("vxsky dry run".to_owned()),
+        handle: "dry-run.vxsky.app".to_owned(),
+        labels: None,
+        viewer: None,
+    };
+
+    let record = post::Record {
+        created_at: "2024-01-01T00:00:00.000Z".to_owned(),
+        embed: None,
+        entities: None,
+        facets: None,
+        labels: None,
+        langs: Some(vec!["en".to_owned()]),
+        reply: None,
+        tags: None,
+        text: STUB_TEXT.to_owned(),
+    };
+
+    TextEmbed {
+        profile,
+        base_url: base_url.to_owned(),
+        post_url,
+        record: Box::new(record),
+        matrix_preview,
+        view_count: None,
+        content_warning: None,
+    }
+}
@@ -0,0 +1,81 @@
+//! Helpers for getting the images out of a post, wherever in the post they happen to be.
+
+use atrium_api::{
+    app::bsky::{
+        embed::{
+            images::{
+                self,
+                ViewImage,
+            },
+            record_with_media::ViewMediaEnum,
+        },
+        feed::{
+            defs::{
+                PostView,
+                PostViewEmbedEnum,
+            },
+            post::RecordEmbedEnum,
+        },
+    },
+    records::Record,
+    types::{
+        BlobRef,
+        TypedBlobRef,
+    },
+};
+
+/// The CDN serving images uploaded to bluesky.
+const CDN_URL: &str = "https://cdn.bsky.app/img";
+
+/// Gets the images attached to a post, returns `None` if the post has none.
+///
+/// The hydrated view of the embed is used where possible, which covers posts with just images and
+/// quote posts with images attached. If the view is missing the images are taken from the post's
+/// record instead, building the CDN links from the blobs ourselves.
+pub fn post_images(view: &PostView) -> Option<Vec<ViewImage>> {
+    let images = match &view.embed {
+        Some(PostViewEmbedEnum::AppBskyEmbedImagesView(embed)) => embed.images.to_owned(),
+        Some(PostViewEmbedEnum::AppBskyEmbedRecordWithMediaView(embed)) => match &embed.media {
+            ViewMediaEnum::AppBskyEmbedImagesView(media) => media.images.to_owned(),
+            _ => return None,
+        },
+        Some(_) => return None,
+        None => record_images(view)?,
+    };
+
+    (!images.is_empty()).then_some(images)
+}
+
+/// Builds the image views for the images embedded in a post's record.
+fn record_images(view: &PostView) -> Option<Vec<ViewImage>> {
+    let Record::AppBskyFeedPost(record) = &view.record else {
+        return None;
+    };
+
+    let Some(RecordEmbedEnum::AppBskyEmbedImagesMain(embed)) = &record.embed else {
+        return None;
+    };
+
+    let images = embed
+        .images
+        .iter()
+        .map(|image| image_view(&view.author.did, image))
+        .collect();
+
+    Some(images)
+}
+
+/// Builds the view of an image from its blob, linking to where the CDN serves it.
+fn image_view(did: &str, image: &images::Image) -> ViewImage {
+    let cid = match &image.image {
+        BlobRef::Typed(TypedBlobRef::Blob(blob)) => &blob.r#ref.link,
+        BlobRef::Untyped(blob) => &blob.cid,
+    };
+
+    ViewImage {
+        alt: image.alt.to_owned(),
+        aspect_ratio: image.aspect_ratio.to_owned(),
+        fullsize: format!("{CDN_URL}/feed_fullsize/plain/{did}/{cid}@jpeg"),
+        thumb: format!("{CDN_URL}/feed_thumbnail/plain/{did}/{cid}@jpeg"),
+    }
+}
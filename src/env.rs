@@ -0,0 +1,65 @@
+//! Helpers for reading configuration from environment variables.
+
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fmt::Display,
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+
+/// Loads environment variables from a `.env` file, followed by a `.env.{VXSKY_ENV}` file (e.g.
+/// `.env.production`) if `VXSKY_ENV` is set. Values in the environment specific file take
+/// precedence over the base file, and anything already set in the real environment takes
+/// precedence over both.
+pub fn load_dotenv() {
+    let existing: HashSet<OsString> = std::env::vars_os().map(|(key, _)| key).collect();
+    dotenv::dotenv().ok();
+
+    let Ok(environment) = std::env::var("VXSKY_ENV") else {
+        return;
+    };
+
+    // dotenv never overrides variables that are already set, so the base file is unloaded and
+    // loaded again after the environment specific one to let that take precedence.
+    for (key, _) in std::env::vars_os() {
+        if !existing.contains(&key) {
+            std::env::remove_var(key);
+        }
+    }
+
+    dotenv::from_filename(format!(".env.{environment}")).ok();
+    dotenv::dotenv().ok();
+}
+
+/// Reads a required environment variable, failing if it isn't set.
+pub fn required(name: &str) -> anyhow::Result<String> {
+    std::env::var(name).map_err(|_| anyhow!("The {name} environment variable is required."))
+}
+
+/// Reads an optional environment variable, returning `None` if it isn't set.
+pub fn optional(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// Reads a boolean flag from an environment variable, which is only enabled when set to `true`.
+pub fn flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|value| value == "true")
+        .unwrap_or(false)
+}
+
+/// Reads and parses an environment variable, falling back to `default` if it isn't set.
+pub fn parse<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|err| anyhow!("The {name} environment variable is invalid: {err}")),
+        Err(_) => Ok(default),
+    }
+}
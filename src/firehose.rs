@@ -0,0 +1,218 @@
+//! A minimal client for the bluesky firehose (`com.atproto.sync.subscribeRepos`), decoding only as
+//! much of each commit as is needed to spot new likes, reposts and replies to watched posts.
+
+use std::{
+    io::Cursor,
+    time::Duration,
+};
+
+use anyhow::bail;
+use futures::StreamExt;
+use log::{
+    info,
+    warn,
+};
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::updates::PostUpdates;
+
+/// The path of the firehose endpoint on a relay.
+const SUBSCRIBE_REPOS_PATH: &str = "/xrpc/com.atproto.sync.subscribeRepos";
+
+/// How long the firehose can go without sending anything before the connection is assumed dead.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait before reconnecting after the firehose connection fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The collections whose new records change the counts of another post.
+const COUNTED_COLLECTIONS: [&str; 3] = [
+    "app.bsky.feed.like/",
+    "app.bsky.feed.repost/",
+    "app.bsky.feed.post/",
+];
+
+/// The change a new record makes to the counts of the post it refers to.
+#[derive(Debug, Clone, Copy)]
+pub enum CountChange {
+    Like,
+    Repost,
+    Reply,
+}
+
+/// The header every firehose frame starts with, followed by the body for its message type.
+#[derive(Deserialize)]
+struct FrameHeader {
+    op: i64,
+    t: Option<String>,
+}
+
+/// The parts of a `#commit` message needed to find the records it created.
+#[derive(Deserialize)]
+struct Commit {
+    seq: i64,
+    #[serde(rename = "tooBig", default)]
+    too_big: bool,
+    ops: Vec<RepoOp>,
+    /// A CAR file holding the records and repository nodes changed by the commit.
+    #[serde(with = "serde_bytes")]
+    blocks: Vec<u8>,
+}
+
+/// A single change to a repository in a [Commit].
+#[derive(Deserialize)]
+struct RepoOp {
+    action: String,
+    path: String,
+}
+
+/// The fields of a like, repost or post record that point at another post.
+#[derive(Deserialize)]
+struct CountedRecord {
+    #[serde(rename = "$type")]
+    kind: String,
+    subject: Option<StrongRef>,
+    reply: Option<ReplyRef>,
+}
+
+#[derive(Deserialize)]
+struct StrongRef {
+    uri: String,
+}
+
+#[derive(Deserialize)]
+struct ReplyRef {
+    parent: StrongRef,
+}
+
+impl CountedRecord {
+    /// The post this record changes the counts of, and how.
+    fn change(&self) -> Option<(&str, CountChange)> {
+        match self.kind.as_str() {
+            "app.bsky.feed.like" => Some((&self.subject.as_ref()?.uri, CountChange::Like)),
+            "app.bsky.feed.repost" => Some((&self.subject.as_ref()?.uri, CountChange::Repost)),
+            "app.bsky.feed.post" => Some((&self.reply.as_ref()?.parent.uri, CountChange::Reply)),
+            _ => None,
+        }
+    }
+}
+
+/// Follows the firehose for as long as any posts are being watched, reconnecting where it left off
+/// if the connection drops.
+pub async fn run(updates: PostUpdates) {
+    let mut cursor = None;
+
+    loop {
+        if !updates.is_watching() && updates.stop_firehose() {
+            info!("Nothing is watching for post updates, disconnecting from the firehose");
+            return;
+        }
+
+        if let Err(err) = subscribe(&updates, &mut cursor).await {
+            warn!("Firehose connection failed, reconnecting in {RECONNECT_DELAY:?}: {err}");
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+/// Connects to the firehose and applies the commits it sends until nothing is watching anymore,
+/// keeping track of the last commit seen so a reconnect can pick up from there.
+async fn subscribe(updates: &PostUpdates, cursor: &mut Option<i64>) -> anyhow::Result<()> {
+    let url = match cursor {
+        Some(seq) => format!(
+            "{}{SUBSCRIBE_REPOS_PATH}?cursor={seq}",
+            updates.firehose_url()
+        ),
+        None => format!("{}{SUBSCRIBE_REPOS_PATH}", updates.firehose_url()),
+    };
+    let (mut socket, _) = tokio_tungstenite::connect_async(url).await?;
+    info!("Connected to the firehose at {}", updates.firehose_url());
+
+    while updates.is_watching() {
+        let message = match tokio::time::timeout(IDLE_TIMEOUT, socket.next()).await {
+            Ok(Some(message)) => message?,
+            Ok(None) => bail!("The firehose closed the connection"),
+            Err(_) => bail!("The firehose sent nothing for {IDLE_TIMEOUT:?}"),
+        };
+
+        let Message::Binary(frame) = message else {
+            continue;
+        };
+        if let Some(seq) = handle_frame(&frame, updates)? {
+            *cursor = Some(seq);
+        }
+    }
+
+    let _ = socket.close(None).await;
+    Ok(())
+}
+
+/// Decodes a firehose frame and applies any count changes in it, returning the sequence number of
+/// the commit it carried.
+fn handle_frame(frame: &[u8], updates: &PostUpdates) -> anyhow::Result<Option<i64>> {
+    let mut reader = Cursor::new(frame);
+    let header: FrameHeader = ciborium::from_reader(&mut reader)?;
+    if header.op != 1 {
+        bail!("The firehose sent an error frame");
+    }
+    if header.t.as_deref() != Some("#commit") {
+        return Ok(None);
+    }
+
+    let commit: Commit = ciborium::from_reader(&mut reader)?;
+    let creates_counted = commit.ops.iter().any(|op| {
+        op.action == "create"
+            && COUNTED_COLLECTIONS
+                .iter()
+                .any(|collection| op.path.starts_with(collection))
+    });
+
+    // Commits that were too big to include their blocks can't be checked, so they're skipped.
+    if creates_counted && !commit.too_big {
+        for block in car_blocks(&commit.blocks) {
+            // Most blocks are repository nodes rather than records, which don't decode as one.
+            let Ok(record) = ciborium::from_reader::<CountedRecord, _>(block) else {
+                continue;
+            };
+            if let Some((uri, change)) = record.change() {
+                updates.apply(uri, change);
+            }
+        }
+    }
+
+    Ok(Some(commit.seq))
+}
+
+/// Splits a CARv1 file into the data of its blocks, stopping at the first malformed one.
+fn car_blocks(car: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = read_varint(car).and_then(|(header_len, rest)| rest.get(header_len as usize..));
+
+    std::iter::from_fn(move || {
+        let (block_len, after_len) = read_varint(rest?)?;
+        let block = after_len.get(..block_len as usize)?;
+        rest = after_len.get(block_len as usize..);
+        skip_cid(block)
+    })
+}
+
+/// Skips over the CIDv1 at the start of a block, returning the data after it.
+fn skip_cid(block: &[u8]) -> Option<&[u8]> {
+    let (_version, rest) = read_varint(block)?;
+    let (_codec, rest) = read_varint(rest)?;
+    let (_hash_code, rest) = read_varint(rest)?;
+    let (hash_len, rest) = read_varint(rest)?;
+    rest.get(hash_len as usize..)
+}
+
+/// Reads an unsigned LEB128 varint, returning it and the bytes after it.
+fn read_varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0u64;
+    for (index, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (index * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[index + 1..]));
+        }
+    }
+    None
+}
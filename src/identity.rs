@@ -0,0 +1,98 @@
+//! Typed wrappers for the different kinds of bluesky account identifiers, so a handle can't be
+//! accidentally used where a DID is expected and vice versa.
+
+use std::fmt;
+
+use thiserror::Error;
+
+/// Errors that can occur when validating an identifier.
+#[derive(Debug, Error)]
+pub enum IdentityError {
+    #[error("'{0}' is not a valid DID")]
+    InvalidDid(String),
+    #[error("'{0}' is not a valid handle")]
+    InvalidHandle(String),
+}
+
+/// A decentralized identifier that permanently identifies an account (e.g. "did:plc:1234abcd").
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Did(String);
+
+impl TryFrom<String> for Did {
+    type Error = IdentityError;
+
+    /// Validates the general DID syntax of `did:<method>:<identifier>`, without checking that the
+    /// DID actually exists.
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let valid = value
+            .strip_prefix("did:")
+            .and_then(|rest| rest.split_once(':'))
+            .map(|(method, identifier)| {
+                !method.is_empty()
+                    && method.chars().all(|c| c.is_ascii_lowercase())
+                    && !identifier.is_empty()
+                    && !identifier.ends_with([':', '%'])
+                    && identifier
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "._:%-".contains(c))
+            })
+            .unwrap_or(false);
+
+        match valid {
+            true => Ok(Did(value)),
+            false => Err(IdentityError::InvalidDid(value)),
+        }
+    }
+}
+
+impl fmt::Display for Did {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A human readable, domain name based identifier for an account (e.g. "videah.net"), which can
+/// change over time and needs to be resolved to a [Did].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Handle(String);
+
+impl Handle {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for Handle {
+    type Error = IdentityError;
+
+    /// Validates the handle syntax, a domain name with at least two labels where the top level
+    /// label doesn't start with a digit.
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let labels: Vec<_> = value.split('.').collect();
+        let valid_label = |label: &&str| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        };
+
+        let valid = value.len() <= 253
+            && labels.len() >= 2
+            && labels.iter().all(valid_label)
+            && labels
+                .last()
+                .map(|tld| !tld.starts_with(|c: char| c.is_ascii_digit()))
+                .unwrap_or(false);
+
+        match valid {
+            true => Ok(Handle(value.to_ascii_lowercase())),
+            false => Err(IdentityError::InvalidHandle(value)),
+        }
+    }
+}
+
+impl fmt::Display for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
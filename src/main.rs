@@ -1,123 +1,408 @@
 //! Improves multi-image embeds for Bluesky by combining all images into one thumbnail.
 
+mod access_log;
+mod api;
+mod cache;
+mod dry_run;
+mod embed;
+mod env;
+mod firehose;
+mod identity;
+mod middleware;
 mod processing;
+mod resolver;
+mod retry;
+mod session;
+mod state;
+mod stats;
 mod templates;
+mod thread;
+mod updates;
 mod user_agent;
+mod views;
 
-use std::sync::Arc;
+use std::{
+    hash::{
+        Hash,
+        Hasher,
+    },
+    mem,
+    net::SocketAddr,
+};
 
-use anyhow::anyhow;
+use anyhow::Context;
+use askama::Template;
 use atrium_api::{
-    agent::{
-        store::MemorySessionStore,
-        AtpAgent,
-    },
+    agent::Session,
     app::bsky::{
+        actor::{
+            defs::ProfileViewBasic,
+            get_profile,
+        },
         embed::images::ViewImage,
         feed::{
             defs::{
                 PostView,
-                PostViewEmbedEnum::AppBskyEmbedImagesView,
+                ThreadViewPost,
+            },
+            get_author_feed,
+            get_post_thread::{
+                self,
+                OutputThreadEnum,
             },
             get_posts,
+            search_posts,
         },
     },
-    com::atproto::identity::resolve_handle,
+    com::atproto::{
+        identity::resolve_handle,
+        server::get_session,
+    },
     records::Record,
 };
-use atrium_xrpc_client::reqwest::ReqwestClient;
+use atrium_xrpc::error::{
+    ErrorResponseBody,
+    XrpcErrorKind,
+};
 use axum::{
+    body::Bytes,
     extract::{
+        ws::{
+            Message,
+            WebSocket,
+            WebSocketUpgrade,
+        },
+        Host,
         Path,
         Query,
-        State,
     },
     http::{
         header,
+        HeaderMap,
+        HeaderName,
+        HeaderValue,
         StatusCode,
+        Uri,
     },
     response::{
+        Html,
         IntoResponse,
         Redirect,
         Response,
     },
-    routing::get,
+    routing::{
+        get,
+        patch,
+        post,
+    },
+    Extension,
+    Json,
     Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
 use axum_thiserror::ErrorStatus;
+use clap::Parser;
+use futures::{
+    stream,
+    StreamExt,
+    TryStreamExt,
+};
 use image::DynamicImage;
 use log::{
     error,
     info,
+    warn,
+};
+use percent_encoding::{
+    utf8_percent_encode,
+    AsciiSet,
+    NON_ALPHANUMERIC,
+};
+use qrcode::{
+    render::svg,
+    QrCode,
 };
 use rayon::prelude::*;
-use reqwest::Client;
 use serde::Deserialize;
 use thiserror::Error;
 use tokio::net::TcpListener;
+use tower_http::{
+    catch_panic::CatchPanicLayer,
+    set_header::SetResponseHeaderLayer,
+};
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
 
 use crate::{
+    api::{
+        embed_title,
+        ApiError,
+        BotDetection,
+        EmbedCard,
+        ErrorBody,
+        ErrorKind,
+        IframelyResponse,
+        JsonLdArticle,
+        NodeInfo,
+        NodeInfoLink,
+        NodeInfoLinks,
+        OEmbedResponse,
+        PostCounts,
+        ResolvedIdentity,
+        SlackAttachment,
+        StatusInfo,
+        VersionInfo,
+    },
+    identity::{
+        Did,
+        Handle,
+    },
+    processing::CombinedThumbnail,
+    retry::retry_with_backoff,
+    state::{
+        base_url_matches_host,
+        AppState,
+        HostState,
+        ServerConfig,
+    },
     templates::{
+        AmpImageEmbed,
         EmbedAccountGated,
+        EmbedFrame,
+        EmbedPreview,
+        EmbedTest,
+        EmbedTestPost,
         ImageEmbed,
+        Locale,
+        NotFoundPage,
+        OpenGraphTags,
+        PostRedirect,
+        ScriptEmbed,
+        SearchResult,
+        SearchResults,
+        SharePage,
+        TextEmbed,
+        ThreadEmbed,
+        TwitterCard,
     },
+    updates::CountsReceiver,
     user_agent::RequireEmbed,
 };
 
-/// The application state passed to each request handler.
-#[derive(Clone)]
-struct AppState {
-    /// The [AtpAgent] used to make requests to the bluesky API, handles authentication and session
-    /// management.
-    agent: Arc<AtpAgent<MemorySessionStore, ReqwestClient>>,
-    /// The HTTP client used to make requests for images.
-    http_client: Client,
-    /// The base URL for where this application is hosted (e.g. "https://vsky.app").
-    base_url: String,
+/// Command line arguments, which take precedence over the matching environment variables.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// The socket address to listen on, "[::]:8080" can be used for dual-stack or IPv6 only hosts.
+    #[arg(long, env = "VXSKY_BIND_ADDR", default_value = "0.0.0.0:8080")]
+    bind: SocketAddr,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    // Set up logging and load environment variables from a .env file.
-    dotenv::dotenv().ok();
-    let env = env_logger::Env::default().filter_or("RUST_LOG", "info");
-    env_logger::init_from_env(env);
-
-    let listener = TcpListener::bind("0.0.0.0:8080").await?;
-
-    let base_url = std::env::var("VXSKY_BASE_URL")
-        .map_err(|_| anyhow!("The VXSKY_BASE_URL environment variable is required."))?;
-
-    let state = AppState {
-        agent: Arc::new(AtpAgent::new(
-            ReqwestClient::new("https://bsky.social"),
-            MemorySessionStore::default(),
-        )),
-        http_client: Client::new(),
-        base_url,
-    };
+    // Set up logging and load environment variables from the .env files.
+    env::load_dotenv();
+    // Logs from the `log` crate are forwarded to tracing, so they pick up the fields of whatever
+    // span they happen in.
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
 
-    // Get Bluesky account credentials for API access.
-    let identifier = std::env::var("VXSKY_IDENTIFIER").map_err(|_| {
-        anyhow!("The VXSKY_IDENTIFIER environment variable is required, either an email or handle.")
-    })?;
+    let config = ServerConfig::from_env()?;
+
+    // Error reporting is optional, the guard needs to live for the whole process so events get
+    // flushed before exiting.
+    let _sentry = config.sentry_dsn.to_owned().map(|dsn| {
+        sentry::init((
+            dsn,
+            sentry::ClientOptions {
+                release: sentry::release_name!(),
+                ..Default::default()
+            },
+        ))
+    });
+
+    // Arguments are parsed after loading the .env files so they can fill in the defaults.
+    let args = Args::parse();
+    let listener = TcpListener::bind(args.bind).await?;
+
+    // Image processing runs on rayon's global pool, which is kept smaller than the machine so the
+    // async runtime still gets CPU time.
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(config.rayon_threads)
+        .build_global()?;
+
+    let state = AppState::from_env().await?;
 
-    let password = std::env::var("VXSKY_APP_PASSWORD")
-        .map_err(|_| anyhow!("The VXSKY_APP_PASSWORD environment variable is required."))?;
+    // Sending the process SIGUSR1 dumps some statistics to the log, handy for checking on a running
+    // server without restarting it.
+    #[cfg(unix)]
+    tokio::spawn(stats::log_on_sigusr1(state.clone()));
+
+    // Keep an eye on the bluesky session so operators can be told when it expires, optionally
+    // through a webhook posting to Slack, Discord or the like.
+    if !state.dry_run {
+        tokio::spawn(session::watch_session(
+            state.clone(),
+            config.webhook_url.to_owned(),
+        ));
+    }
+
+    // Expired pages are only dropped from the template cache when they're requested again, so
+    // sweep out the ones for posts nobody is asking for anymore in the background.
+    let template_cache = state.template_cache.clone();
+    if !template_cache.ttl().is_zero() {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(template_cache.ttl());
+            loop {
+                interval.tick().await;
+                template_cache.purge_expired();
+            }
+        });
+    }
+
+    let app = build_app(state, &config);
+
+    // Terminate TLS ourselves if we've been given a certificate, otherwise we expect to be sitting
+    // behind a reverse proxy that handles it for us.
+    match config.tls {
+        Some(tls) => {
+            let config = RustlsConfig::from_pem_file(tls.cert, tls.key).await?;
+            info!("Listening on {} with TLS", listener.local_addr()?);
+            axum_server::from_tcp_rustls(listener.into_std()?, config)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        None => {
+            info!("Listening on {}", listener.local_addr()?);
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, app).await?;
+        }
+    }
 
-    // Authenticate with the bluesky API and store the session.
-    state.agent.login(identifier, password).await?;
+    Ok(())
+}
 
-    let app = Router::new()
+/// Builds the router with every route and layer the application uses, ready to be served or called
+/// directly with `tower::ServiceExt::oneshot`. The state is already applied, so the returned
+/// router doesn't carry the [AppState] type around with it.
+fn build_app(state: AppState, config: &ServerConfig) -> Router {
+    let mut app = Router::new()
         .route("/", get(index_redirect))
+        .route("/profile/:identifier", get(profile_redirect))
+        .route("/profile/:identifier/post", get(missing_post_redirect))
+        .route("/profile/:identifier/post/", get(missing_post_redirect))
         .route("/profile/:identifier/post/:post_id", get(embed_image))
+        .route(
+            "/profile/:identifier/post/:post_id/card.json",
+            get(embed_card),
+        )
+        .route("/profile/:identifier/post/:post_id/card", get(slack_card))
+        .route(
+            "/profile/:identifier/post/:post_id/json-ld",
+            get(embed_json_ld),
+        )
+        .route(
+            "/profile/:identifier/post/:post_id/opengraph.html",
+            get(opengraph_tags),
+        )
+        .route(
+            "/profile/:identifier/post/:post_id/twitter-card",
+            get(twitter_card),
+        )
+        .route(
+            "/profile/:identifier/post/:post_id/preview",
+            get(embed_preview),
+        )
+        .route("/profile/:identifier/post/:post_id/frame", get(embed_frame))
+        .route("/profile/:identifier/post/:post_id/amp", get(embed_amp))
+        .route("/profile/:identifier/post/:post_id/share", get(share_page))
+        .route(
+            "/profile/:identifier/post/:post_id/embed.js",
+            get(embed_script),
+        )
+        .route(
+            "/profile/:identifier/post/:post_id/thumbnail.png",
+            get(post_thumbnail),
+        )
+        .route("/profile/:identifier/feed.png", get(render_feed_image))
+        .route(
+            "/profile/:identifier/feed-mosaic.png",
+            get(render_feed_image),
+        )
+        .route("/ws/post/:identifier/:post_id", get(post_updates))
         .route("/render-combined-image.png", get(render_combined_image))
+        .route("/render-animated-image.gif", get(render_animated_image))
         .route("/gated.png", get(gated_image))
+        .route("/text-only.png", get(text_only_image))
+        .route("/share.js", get(share_script))
+        .route("/search", get(search))
+        .route("/embed-test", get(embed_test))
+        .route("/oembed.json", get(oembed))
+        .route("/health", get(health))
+        .route("/healthz", get(health))
+        .route("/version", get(version))
+        .route("/status", get(status))
+        .route("/metrics", get(metrics))
+        .route("/diagnostics/bot-detection", get(bot_detection))
+        .route("/session", patch(inject_session))
+        .route("/api/v1/user-agents", get(list_user_agents))
+        .route("/api/v1/resolve/:identifier", get(resolve))
+        .route("/api/v1/batch-embed", post(batch_embed))
+        .route("/about", get(index_redirect))
+        .route("/.well-known/nodeinfo", get(nodeinfo_discovery))
+        .route("/nodeinfo/2.0", get(nodeinfo))
+        .fallback(handle_404)
+        .layer(CatchPanicLayer::custom(middleware::handle_panic))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::check_host,
+        ))
+        .layer(axum::middleware::from_fn(middleware::handle_error))
+        .layer(axum::middleware::from_fn(middleware::compress_images))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::log_request,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::record_latency,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::access_log,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::content_security_policy,
+        ))
+        .layer(middleware::frame_options())
+        .layer(middleware::security_header(
+            header::X_CONTENT_TYPE_OPTIONS,
+            "nosniff",
+        ))
+        .layer(middleware::security_header(
+            header::REFERRER_POLICY,
+            "strict-origin-when-cross-origin",
+        ))
         .with_state(state);
 
-    info!("Listening on {}", listener.local_addr()?);
-    axum::serve(listener, app).await?;
-    Ok(())
+    // Browsers only honour HSTS over HTTPS, so it's only sent when we know the connection is secure
+    // either because we terminate TLS or the reverse proxy in front of us does.
+    if config.tls.is_some() || config.behind_tls_proxy {
+        app = app.layer(SetResponseHeaderLayer::overriding(
+            header::STRICT_TRANSPORT_SECURITY,
+            HeaderValue::from_static("max-age=31536000; includeSubDomains"),
+        ));
+    }
+
+    if !config.hide_powered_by {
+        app = app.layer(SetResponseHeaderLayer::overriding(
+            HeaderName::from_static("x-powered-by"),
+            HeaderValue::from_static(concat!("vxsky/", env!("CARGO_PKG_VERSION"))),
+        ));
+    }
+
+    app
 }
 
 /// Error type that defines possible failure states for the handlers in this application.
@@ -126,27 +411,117 @@ enum EmbedError {
     #[error("Failed to retrieve DID from identifier")]
     #[status(StatusCode::BAD_REQUEST)]
     ResolveHandleError,
+    #[error("'{0}' is not a valid handle or DID")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidHandle(String),
     #[error("Failed to retrieve post: {0}")]
     #[status(StatusCode::INTERNAL_SERVER_ERROR)]
     PostRetrievalError(#[from] atrium_xrpc::error::Error<get_posts::Error>),
     #[error("The API request was successful but no post was returned")]
     #[status(StatusCode::NO_CONTENT)]
     NoPostInResponse,
+    #[error("This post is unavailable because it has been blocked")]
+    #[status(StatusCode::GONE)]
+    PostBlocked,
+    #[error("This post is unavailable because it has been deleted")]
+    #[status(StatusCode::GONE)]
+    PostDeleted,
+    #[error("This account only shows its posts to people who are signed in")]
+    #[status(StatusCode::FORBIDDEN)]
+    AccountGated,
+    #[error("The URL is not a link to a bluesky post")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidPostUrl,
+    #[error("{0:?} is not the ATUri of a bluesky post")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidPostUri(String),
     #[error("Post has no images, cannot create thumbnail")]
     #[status(StatusCode::UNPROCESSABLE_ENTITY)]
     PostHasNoImages,
+    #[error("Post has no animated GIFs")]
+    #[status(StatusCode::UNPROCESSABLE_ENTITY)]
+    PostHasNoGif,
     #[error("The record handler for this post's embeds is not implemented")]
     #[status(StatusCode::NOT_IMPLEMENTED)]
     UnimplementedRecordHandler,
     #[error("An error occurred while generating a combined thumbnail: {0}")]
     #[status(StatusCode::INTERNAL_SERVER_ERROR)]
     ThumbnailProcessingError(#[from] processing::ProcessingError),
-    #[error("An error occurred while loading an image: {0}")]
+    #[error("The image is {0} bytes, more than the limit of {1} bytes")]
+    #[status(StatusCode::UNPROCESSABLE_ENTITY)]
+    ThumbnailTooLarge(u64, u64),
+    #[error("Too many connections are watching posts for updates")]
+    #[status(StatusCode::SERVICE_UNAVAILABLE)]
+    TooManyConnections,
+    #[error("Failed to generate a QR code: {0}")]
     #[status(StatusCode::INTERNAL_SERVER_ERROR)]
-    ThumbnailLoadingError(#[from] image::ImageError),
+    QrCodeError(#[from] qrcode::types::QrError),
     #[error("Could not retrieve image bytes from response")]
     #[status(StatusCode::INTERNAL_SERVER_ERROR)]
     ThumbnailBytesError(#[from] reqwest::Error),
+    #[error("Failed to retrieve author feed: {0}")]
+    #[status(StatusCode::INTERNAL_SERVER_ERROR)]
+    FeedRetrievalError(#[from] atrium_xrpc::error::Error<get_author_feed::Error>),
+    #[error("Failed to retrieve profile: {0}")]
+    #[status(StatusCode::INTERNAL_SERVER_ERROR)]
+    ProfileRetrievalError(#[from] atrium_xrpc::error::Error<get_profile::Error>),
+    #[error("Failed to retrieve thread: {0}")]
+    #[status(StatusCode::INTERNAL_SERVER_ERROR)]
+    ThreadRetrievalError(#[from] atrium_xrpc::error::Error<get_post_thread::Error>),
+    #[error("Failed to search posts: {0}")]
+    #[status(StatusCode::INTERNAL_SERVER_ERROR)]
+    SearchError(#[from] atrium_xrpc::error::Error<search_posts::Error>),
+    #[error("No posts were found matching the search query")]
+    #[status(StatusCode::NOT_FOUND)]
+    NoSearchResults,
+    #[error("A valid admin token is required")]
+    #[status(StatusCode::UNAUTHORIZED)]
+    Unauthorized,
+    #[error("Admin endpoints are disabled")]
+    #[status(StatusCode::NOT_FOUND)]
+    AdminDisabled,
+    #[error("The session was rejected: {0}")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidSession(String),
+    #[error("Failed to resume the session: {0}")]
+    #[status(StatusCode::BAD_REQUEST)]
+    SessionResumeError(#[from] atrium_xrpc::error::Error<get_session::Error>),
+    #[error("Failed to render embed page: {0}")]
+    #[status(StatusCode::INTERNAL_SERVER_ERROR)]
+    TemplateRenderError(#[from] askama::Error),
+}
+
+impl From<EmbedError> for ApiError {
+    fn from(err: EmbedError) -> Self {
+        let message = err.to_string();
+        let status = match err.into_response().status() {
+            // A 204 can't carry a body, so the JSON error goes out as the closest actual error.
+            StatusCode::NO_CONTENT => StatusCode::NOT_FOUND,
+            status => status,
+        };
+
+        ApiError {
+            code: status.as_u16(),
+            message,
+            kind: ErrorKind::from(status),
+        }
+    }
+}
+
+// Errors are compared by variant only, a lot of them wrap errors from other crates that can't be
+// compared themselves and what went wrong is what matters when checking an error anyway.
+impl PartialEq for EmbedError {
+    fn eq(&self, other: &Self) -> bool {
+        mem::discriminant(self) == mem::discriminant(other)
+    }
+}
+
+impl Eq for EmbedError {}
+
+impl Hash for EmbedError {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+    }
 }
 
 /// Parameters passed to the combined image thumbnail rendering endpoint to tell it what post it
@@ -154,8 +529,15 @@ enum EmbedError {
 #[derive(Deserialize)]
 pub struct RenderImageParams {
     pub uri: String,
+    /// Overrides the blur strength of the background, snapped to the nearest of [BLUR_STEPS].
+    pub blur: Option<f32>,
 }
 
+/// The blur strengths that can be asked for with the `blur` query parameter. Only allowing a few
+/// keeps the number of cached variants of each thumbnail small, so random values can't be used to
+/// force a fresh render on every request.
+const BLUR_STEPS: [f32; 5] = [0.0, 10.0, 25.0, 50.0, 100.0];
+
 /// Handler for taking multiple bluesky post images and combining them into one thumbnail.
 ///
 /// This is its own endpoint rather than being part of the `embed_image` handler because it's
@@ -163,93 +545,660 @@ pub struct RenderImageParams {
 /// don't support base64 encoded images unfortunately.
 async fn render_combined_image(
     params: Query<RenderImageParams>,
-    State(state): State<AppState>,
+    HostState(state): HostState,
+) -> Result<Response, EmbedError> {
+    // An ATUri without a record key points at a whole account rather than a post.
+    if let Some(authority) = missing_rkey(&params.uri) {
+        let identifier = utf8_percent_encode(authority, IDENTIFIER_ENCODE_SET);
+        return Ok(Redirect::temporary(&format!("/profile/{identifier}")).into_response());
+    }
+
+    // Blur is tuned on a copy of the state so it applies to just this request.
+    let mut state = state;
+    if let Some(blur) = params.blur.filter(|blur| blur.is_finite()) {
+        state.processing.blur_radius = BLUR_STEPS
+            .into_iter()
+            .min_by(|a, b| (a - blur).abs().total_cmp(&(b - blur).abs()))
+            .unwrap_or_default();
+    }
+
+    let image = render_thumbnail(&params.uri, &state).await.map_err(|err| {
+        error!(
+            "Failed to render combined image for post {}: {err}",
+            params.uri
+        );
+        err
+    })?;
+
+    Ok(image.into_response())
+}
+
+/// Characters that are percent-encoded when putting an identifier back into a path, leaving the
+/// ones that show up in valid handles and DIDs alone so the links stay readable.
+const IDENTIFIER_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b':');
+
+/// Utility function that returns the authority (the DID or handle) of an ATUri that doesn't have
+/// a record key, like `at://videah.net` or `at://videah.net/app.bsky.feed.post`.
+fn missing_rkey(uri: &str) -> Option<&str> {
+    let path = uri.strip_prefix("at://")?;
+    let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+    let authority = segments.next()?;
+    let _collection = segments.next();
+
+    match segments.next() {
+        Some(_) => None,
+        None => Some(authority),
+    }
+}
+
+/// Handler that serves the same combined thumbnail as `render_combined_image`, but under the same
+/// path as the bluesky post so the URL is readable and can be cached by CDNs keyed on path.
+async fn post_thumbnail(
+    Path((identifier, post_id)): Path<(String, String)>,
+    HostState(state): HostState,
 ) -> Result<impl IntoResponse, EmbedError> {
-    let response = state
-        .agent
-        .api
-        .app
-        .bsky
-        .feed
-        .get_posts(get_posts::Parameters {
-            uris: vec![params.uri.to_owned()],
-        })
+    let aturi = get_aturi(&identifier, &post_id, &state).await?;
+    render_thumbnail(&aturi, &state).await
+}
+
+/// Utility function that fetches a post's images and combines them into one thumbnail image
+/// response.
+async fn render_thumbnail(uri: &String, state: &AppState) -> Result<CombinedThumbnail, EmbedError> {
+    // The blur strength can be changed per request, so it has to be part of the key.
+    let cache_key = format!("{uri}#{}", state.processing.blur_radius);
+    if let Some(image) = state.image_cache.get(&cache_key).await {
+        return Ok(image);
+    }
+
+    let post = get_post(uri, state).await?;
+    let images = embed::post_images(&post).ok_or(EmbedError::PostHasNoImages)?;
+
+    let span = tracing::info_span!(
+        "render_thumbnail",
+        uri = %uri,
+        author = %post.author.did,
+        image_count = images.len(),
+    );
+
+    let obscured = content_warning(&post).is_some();
+    let image = combine_thumbnails(&images, obscured, state)
+        .instrument(span)
         .await?;
 
-    let post = response.posts.first().ok_or(EmbedError::NoPostInResponse)?;
-    let embed = post.embed.as_ref().ok_or(EmbedError::PostHasNoImages)?;
-    match embed {
-        AppBskyEmbedImagesView(view) => {
-            let tasks: Vec<_> = view
-                .images
-                .iter()
-                .map(|image| get_thumbnail(&state, image))
-                .collect();
-
-            let results = futures::future::join_all(tasks).await;
-            let images: Result<Vec<_>, _> = results.into_iter().collect();
-
-            // TODO: If there is just one image, just redirect to the post.
-            // if images.len() == 1 {
-            //     let post_url = format!("https://bsky.app/profile/{identifier}/post/{post_id}");
-            //     return Ok(Redirect::temporary(&post_url));
-            // }
-
-            let image = processing::generate_combined_thumbnail(images?)?;
-            let bytes = image.to_bytes().to_owned();
-
-            Ok(([(header::CONTENT_TYPE, "image/png")], bytes))
+    state.image_cache.insert(cache_key, image.clone()).await;
+
+    Ok(image)
+}
+
+/// Utility function that downloads all of a post's images and composites them into one thumbnail,
+/// blurring the images beyond recognition if `obscured` is set. Images that fail to download are
+/// left out, so this only fails if none of them could be downloaded.
+async fn combine_thumbnails(
+    images: &[ViewImage],
+    obscured: bool,
+    state: &AppState,
+) -> Result<CombinedThumbnail, EmbedError> {
+    info!("Combining {} images into a thumbnail", images.len());
+    let tasks: Vec<_> = images
+        .iter()
+        .enumerate()
+        .map(|(index, image)| async move { (index, get_thumbnail(state, image).await) })
+        .collect();
+
+    // Each image is decoded as soon as its download finishes instead of waiting on the slowest
+    // one, they're tagged with their index so the grid still comes out in the post's order. Only a
+    // limited number are downloaded at once so posts with lots of images can't hog the connection
+    // pool.
+    let mut tasks = stream::iter(tasks).buffer_unordered(state.processing.max_concurrent_downloads);
+    let mut downloaded = Vec::with_capacity(images.len());
+    let mut last_error = None;
+    while let Some((index, result)) = tasks.next().await {
+        match result {
+            Ok(thumbnail) => downloaded.push((index, thumbnail.image)),
+            Err(err) => {
+                warn!("Leaving image {index} out of the thumbnail: {err}");
+                last_error = Some(err);
+            }
         }
-        _ => Err(EmbedError::UnimplementedRecordHandler),
     }
+
+    if downloaded.is_empty() {
+        return Err(last_error.unwrap_or(EmbedError::PostHasNoImages));
+    }
+
+    downloaded.sort_by_key(|(index, _)| *index);
+    let images: Vec<_> = downloaded.into_iter().map(|(_, image)| image).collect();
+
+    // TODO: If there is just one image, just redirect to the post.
+    // if images.len() == 1 {
+    //     let post_url = format!("https://bsky.app/profile/{identifier}/post/{post_id}");
+    //     return Ok(Redirect::temporary(&post_url));
+    // }
+
+    Ok(processing::generate_combined_thumbnail(
+        images,
+        &state.processing,
+        obscured,
+    )?)
+}
+
+/// A thumbnail downloaded from the Bluesky CDN.
+struct Thumbnail {
+    /// The decoded image used for compositing, only the first frame if the image is animated.
+    image: DynamicImage,
+    /// The original bytes of the image if it's an animated GIF, so the animation can be served
+    /// as-is to platforms that support animated images.
+    gif: Option<Bytes>,
 }
 
 /// Utility function to download a thumbnail from the Bluesky CDN using a ViewImage's `thumb` and
-/// return a DynamicImage.
-async fn get_thumbnail(state: &AppState, image: &ViewImage) -> Result<DynamicImage, EmbedError> {
+/// decode it for compositing.
+async fn get_thumbnail(state: &AppState, image: &ViewImage) -> Result<Thumbnail, EmbedError> {
     let response = state.http_client.get(&image.thumb).send().await?;
+
+    // Going by the reported length means oversized images are turned away without downloading
+    // any of them.
+    let max_bytes = state.processing.max_image_bytes;
+    if let Some(length) = response
+        .content_length()
+        .filter(|length| *length > max_bytes)
+    {
+        return Err(EmbedError::ThumbnailTooLarge(length, max_bytes));
+    }
+
+    // Slow downloads are usually a sign of CDN trouble, which is worth knowing about.
+    let start = std::time::Instant::now();
     let bytes = response.bytes().await?;
-    image::load_from_memory(&bytes).map_err(EmbedError::ThumbnailLoadingError)
+    let elapsed = start.elapsed();
+    if elapsed > state.processing.slow_download_threshold {
+        warn!("Slow image download: {} took {elapsed:?}", image.thumb);
+    }
+
+    let thumbnail = match image.thumb.ends_with(".gif") {
+        true => Thumbnail {
+            image: processing::decode_gif_first_frame(&bytes)?,
+            gif: Some(bytes),
+        },
+        false => Thumbnail {
+            image: processing::decode_image(&bytes)?,
+            gif: None,
+        },
+    };
+
+    processing::validate_dimensions(&thumbnail.image)?;
+    Ok(thumbnail)
 }
 
+/// Parameters passed to the feed image endpoint.
+#[derive(Deserialize)]
+pub struct FeedImageParams {
+    /// The cursor returned by a previous page, used to fetch the next page of posts.
+    pub cursor: Option<String>,
+}
+
+/// How many posts are combined into each page of the feed image.
+const FEED_IMAGE_PAGE_SIZE: i32 = 4;
+
+/// Handler that combines the first image of each of an account's most recent posts with images
+/// into one thumbnail, served as both `feed.png` and `feed-mosaic.png`. The AT protocol cursor for
+/// the next page of posts is returned in a `Link: <...>; rel="next"` header so clients can page
+/// through the rest of the feed.
+async fn render_feed_image(
+    Path(identifier): Path<String>,
+    params: Query<FeedImageParams>,
+    HostState(state): HostState,
+) -> Result<impl IntoResponse, EmbedError> {
+    let did = resolve_identifier(&identifier, &state).await?;
+    let response = retry_with_backoff(
+        || {
+            state
+                .agent
+                .api
+                .app
+                .bsky
+                .feed
+                .get_author_feed(get_author_feed::Parameters {
+                    actor: did.to_string(),
+                    cursor: params.cursor.to_owned(),
+                    filter: Some("posts_with_media".to_owned()),
+                    limit: Some(FEED_IMAGE_PAGE_SIZE),
+                })
+        },
+        API_MAX_RETRIES,
+    )
+    .await?;
+
+    let first_images: Vec<_> = response
+        .feed
+        .iter()
+        // Posts with content warnings are left out rather than blurring the whole feed image.
+        .filter(|item| content_warning(&item.post).is_none())
+        .filter_map(|item| embed::post_images(&item.post)?.into_iter().next())
+        .collect();
+
+    if first_images.is_empty() {
+        return Err(EmbedError::PostHasNoImages);
+    }
+
+    let tasks: Vec<_> = first_images
+        .iter()
+        .map(|image| get_thumbnail(&state, image))
+        .collect();
+
+    // Feed images keep the order of the feed, so they're buffered in order.
+    let images: Vec<_> = stream::iter(tasks)
+        .buffered(state.processing.max_concurrent_downloads)
+        .map_ok(|thumbnail| thumbnail.image)
+        .try_collect()
+        .await?;
+
+    let image = processing::generate_combined_thumbnail(images, &state.processing, false)?;
+
+    let mut headers = HeaderMap::new();
+    if let Some(cursor) = &response.cursor {
+        let identifier = utf8_percent_encode(&identifier, NON_ALPHANUMERIC);
+        let cursor = utf8_percent_encode(cursor, NON_ALPHANUMERIC);
+        let next = format!(
+            "<{}/profile/{identifier}/feed.png?cursor={cursor}>; rel=\"next\"",
+            state.base_url
+        );
+        if let Ok(next) = HeaderValue::from_str(&next) {
+            headers.insert(header::LINK, next);
+        }
+    }
+
+    Ok((headers, image))
+}
+
+/// Handler that serves the original animated GIF from a post, for platforms that support animated
+/// images in embed cards. Only the first GIF in the post is used, since animations can't be
+/// combined into a single thumbnail like still images can.
+async fn render_animated_image(
+    params: Query<RenderImageParams>,
+    HostState(state): HostState,
+) -> Result<impl IntoResponse, EmbedError> {
+    let post = get_post(&params.uri, &state).await?;
+    let images = embed::post_images(&post).ok_or(EmbedError::PostHasNoImages)?;
+    let image = images
+        .iter()
+        .find(|image| image.thumb.ends_with(".gif"))
+        .ok_or(EmbedError::PostHasNoGif)?;
+
+    let thumbnail = get_thumbnail(&state, image).await?;
+    let bytes = thumbnail.gif.ok_or(EmbedError::PostHasNoGif)?;
+
+    Ok(([(header::CONTENT_TYPE, "image/gif")], bytes))
+}
+
+/// How many times a failed request to the bluesky API is retried before giving up.
+const API_MAX_RETRIES: u32 = 3;
+
 /// Utility function to get a post from the bluesky API given an ATUri.
 async fn get_post(uri: &String, state: &AppState) -> Result<PostView, EmbedError> {
+    let response = retry_with_backoff(
+        || {
+            state
+                .agent
+                .api
+                .app
+                .bsky
+                .feed
+                .get_posts(get_posts::Parameters {
+                    uris: vec![uri.to_owned()],
+                })
+        },
+        API_MAX_RETRIES,
+    )
+    .await?;
+
+    let err = match response.posts.first() {
+        Some(post) => return Ok(post.to_owned()),
+        None => match get_unavailable_post(uri, state).await {
+            Err(err @ (EmbedError::PostDeleted | EmbedError::NoPostInResponse)) => err,
+            result => return result,
+        },
+    };
+
+    // The bluesky API may not have indexed accounts on other PDS instances at all, so only then is
+    // the post looked for on the account's own PDS. An account it does know about really did delete
+    // the post, or was taken down, so that's left as is.
+    if !is_unknown_account(uri, state).await {
+        return Err(err);
+    }
+
+    resolver::get_post(uri, &state.resolver_client)
+        .await
+        .map_err(|pds_err| {
+            warn!("Couldn't get {uri} from its PDS either: {pds_err:?}");
+            err
+        })
+}
+
+/// A post looked up from the path of a bluesky link, already checked to be a regular post from an
+/// account that doesn't require signing in to see.
+struct ResolvedPost {
+    /// The ATUri of the post.
+    aturi: String,
+    /// The human clickable link to the post.
+    post_url: String,
+    view: PostView,
+    record: Box<atrium_api::app::bsky::feed::post::Record>,
+}
+
+/// Utility function that fetches the post a bluesky link points to, for handlers that build their
+/// response straight from the post rather than through [build_embed].
+async fn resolve_post(
+    identifier: &str,
+    post_id: &str,
+    state: &AppState,
+) -> Result<ResolvedPost, EmbedError> {
+    let post_url = format!("https://bsky.app/profile/{identifier}/post/{post_id}");
+    let aturi = get_aturi(identifier, post_id, state).await?;
+    let view = get_post(&aturi, state).await?;
+
+    if requires_authentication(&view.author) {
+        return Err(EmbedError::AccountGated);
+    }
+
+    let record = match &view.record {
+        Record::AppBskyFeedPost(record) => record.to_owned(),
+        _ => return Err(EmbedError::UnimplementedRecordHandler),
+    };
+
+    Ok(ResolvedPost {
+        aturi,
+        post_url,
+        view,
+        record,
+    })
+}
+
+/// Utility function to check whether the bluesky API has never heard of the account that made a
+/// post, as opposed to the account being taken down or deactivated.
+async fn is_unknown_account(uri: &str, state: &AppState) -> bool {
+    let Some(did) = uri
+        .strip_prefix("at://")
+        .and_then(|path| path.split('/').next())
+    else {
+        return false;
+    };
+
+    let response = state
+        .agent
+        .api
+        .app
+        .bsky
+        .actor
+        .get_profile(get_profile::Parameters {
+            actor: did.to_owned(),
+        })
+        .await;
+
+    match response {
+        Err(atrium_xrpc::error::Error::XrpcResponse(response)) => matches!(
+            response.error,
+            Some(XrpcErrorKind::Undefined(ErrorResponseBody {
+                message: Some(message),
+                ..
+            })) if message == "Profile not found"
+        ),
+        _ => false,
+    }
+}
+
+/// Utility function to find out why `getPosts` didn't return a post, since it silently leaves out
+/// posts that are blocked or deleted. The thread endpoint tells us which of the two it is, a
+/// deleted post's record is tombstoned and shows up as not found.
+async fn get_unavailable_post(uri: &str, state: &AppState) -> Result<PostView, EmbedError> {
     let response = state
         .agent
         .api
         .app
         .bsky
         .feed
-        .get_posts(get_posts::Parameters {
-            uris: vec![uri.to_owned()],
+        .get_post_thread(get_post_thread::Parameters {
+            depth: Some(0),
+            parent_height: Some(0),
+            uri: uri.to_owned(),
         })
-        .await?;
+        .await
+        // This is only used to explain a missing post, so if it fails we stick with that.
+        .map_err(|_| EmbedError::NoPostInResponse)?;
 
-    let post = response.posts.first().ok_or(EmbedError::NoPostInResponse)?;
+    match response.thread {
+        OutputThreadEnum::AppBskyFeedDefsThreadViewPost(thread) => Ok(thread.post),
+        OutputThreadEnum::AppBskyFeedDefsBlockedPost(_) => Err(EmbedError::PostBlocked),
+        OutputThreadEnum::AppBskyFeedDefsNotFoundPost(_) => Err(EmbedError::PostDeleted),
+    }
+}
 
-    Ok(post.to_owned())
+/// Utility function to build the ATUri of a post from the identifier and post ID found in a bluesky
+/// post URL, resolving the identifier to a DID if it's a handle.
+async fn get_aturi(
+    identifier: &str,
+    post_id: &str,
+    state: &AppState,
+) -> Result<String, EmbedError> {
+    let did = resolve_identifier(identifier, state).await?;
+    Ok(format!("at://{did}/app.bsky.feed.post/{post_id}"))
+}
+
+/// Utility function to turn the identifier found in a bluesky profile URL into a DID, resolving it
+/// if it's a handle. Identifiers that aren't a valid DID or handle are rejected up front, rather
+/// than making an API request that's bound to fail.
+async fn resolve_identifier(identifier: &str, state: &AppState) -> Result<Did, EmbedError> {
+    match Did::try_from(identifier.to_owned()) {
+        Ok(did) => Ok(did),
+        Err(_) => {
+            let handle = Handle::try_from(identifier.to_owned())
+                .map_err(|_| EmbedError::InvalidHandle(identifier.to_owned()))?;
+            resolve_handle(&handle, state).await
+        }
+    }
+}
+
+/// Utility function to resolve a handle to the DID of the account it currently points to. Handles
+/// the bluesky API can't resolve, like ones on other PDS instances, are resolved through the
+/// handle's domain instead.
+async fn resolve_handle(handle: &Handle, state: &AppState) -> Result<Did, EmbedError> {
+    let api_err = match resolve_handle_with_api(handle, state).await {
+        Ok(did) => return Ok(did),
+        Err(err) => err,
+    };
+
+    resolver::resolve_handle(handle, &state.resolver_client)
+        .await
+        .map_err(|well_known_err| {
+            warn!("Couldn't resolve {handle} through its domain either: {well_known_err:?}");
+            api_err
+        })
+}
+
+/// Utility function to resolve a handle to a DID through the bluesky API.
+async fn resolve_handle_with_api(handle: &Handle, state: &AppState) -> Result<Did, EmbedError> {
+    let response = retry_with_backoff(
+        || {
+            state
+                .agent
+                .api
+                .com
+                .atproto
+                .identity
+                .resolve_handle(resolve_handle::Parameters {
+                    handle: handle.as_str().to_owned(),
+                })
+        },
+        API_MAX_RETRIES,
+    )
+    .await
+    .with_context(|| format!("resolving handle {handle}"))
+    .map_err(resolve_error)?;
+
+    Did::try_from(response.did)
+        .with_context(|| format!("resolving handle {handle}"))
+        .map_err(resolve_error)
+}
+
+/// Utility function that logs the full chain of an error from resolving an identifier, since it's
+/// turned into the vague [EmbedError::ResolveHandleError] before being returned to the client.
+fn resolve_error(err: anyhow::Error) -> EmbedError {
+    error!("{err:?}");
+    EmbedError::ResolveHandleError
+}
+
+/// Utility function to look up the handle an account with the given DID currently has.
+async fn resolve_did(did: &Did, state: &AppState) -> Result<Handle, EmbedError> {
+    let profile = retry_with_backoff(
+        || {
+            state
+                .agent
+                .api
+                .app
+                .bsky
+                .actor
+                .get_profile(get_profile::Parameters {
+                    actor: did.to_string(),
+                })
+        },
+        API_MAX_RETRIES,
+    )
+    .await?;
+
+    Handle::try_from(profile.handle)
+        .with_context(|| format!("resolving DID {did}"))
+        .map_err(resolve_error)
+}
+
+/// Handler that resolves a handle or DID to both the account's DID and current handle, for browser
+/// clients that can't make the request to bluesky themselves because of CORS.
+async fn resolve(Path(identifier): Path<String>, HostState(state): HostState) -> impl IntoResponse {
+    // Errors need the CORS header too, otherwise browsers hide them from the client.
+    let response = resolve_identity(identifier, &state).await;
+    ([(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")], response)
+}
+
+/// Utility function that resolves an identifier for the [resolve] handler.
+async fn resolve_identity(
+    identifier: String,
+    state: &AppState,
+) -> Result<Json<ResolvedIdentity>, ApiError> {
+    let (did, handle) = match Did::try_from(identifier.to_owned()) {
+        Ok(did) => {
+            let handle = resolve_did(&did, state).await?;
+            (did, handle)
+        }
+        Err(_) => {
+            let handle = Handle::try_from(identifier.to_owned())
+                .map_err(|_| ApiError::from(EmbedError::InvalidHandle(identifier)))?;
+            let did = resolve_handle(&handle, state).await?;
+            (did, handle)
+        }
+    };
+
+    Ok(Json(ResolvedIdentity {
+        did: did.to_string(),
+        handle: handle.to_string(),
+    }))
+}
+
+/// Handler that sends people on to an account's bluesky profile. Profile links using a DID are
+/// resolved to the account's current handle, so people always end up on the canonical URL.
+async fn profile_redirect(
+    Path(identifier): Path<String>,
+    HostState(state): HostState,
+) -> Result<Redirect, EmbedError> {
+    let handle = match Did::try_from(identifier.to_owned()) {
+        Ok(did) => resolve_did(&did, &state).await?,
+        Err(_) => Handle::try_from(identifier.to_owned())
+            .map_err(|_| EmbedError::InvalidHandle(identifier))?,
+    };
+
+    // Handles can change hands, so this can't be a permanent redirect.
+    Ok(Redirect::temporary(&format!(
+        "https://bsky.app/profile/{handle}"
+    )))
+}
+
+/// Handler for post links that are missing their post ID, sending them on to the profile instead.
+async fn missing_post_redirect(Path(identifier): Path<String>) -> Redirect {
+    let identifier = utf8_percent_encode(&identifier, IDENTIFIER_ENCODE_SET);
+    Redirect::temporary(&format!("/profile/{identifier}"))
+}
+
+/// Optional query parameters that can be passed to the `embed_image` handler.
+#[derive(Deserialize)]
+pub struct EmbedParams {
+    /// Where the link is being shared, `matrix` will include extra tags for Matrix link previews.
+    pub via: Option<String>,
+    /// A BCP 47 language tag used to pick a localized embed template, if the post is written in a
+    /// compatible language.
+    pub lang: Option<String>,
+    /// The response format, Iframely can ask for `json` to get structured data instead of HTML.
+    pub format: Option<String>,
+    /// Whether to show the conversation around the post in the embed, up to a couple of the posts
+    /// above it and replies below it.
+    pub thread: Option<bool>,
 }
 
 /// Selector for the `embed_image` handler to determine whether to return an HTML page featuring the
-/// necessary meta tags for an embed card or to 302 Redirect to the post directly.
+/// necessary meta tags for an embed card or to send the visitor on to the post directly.
 enum EmbedRouter {
     /// The request has come from a bot associated with embed cards, so we return an HTML page with
     /// the appropriate meta tags.
-    Embed(Box<ImageEmbed>),
-    /// The request has come from what we think is a real person, so we 302 Redirect to the post
-    /// directly.
-    DirectLink(Redirect),
+    Embed(Box<ImageEmbed>, Option<Locale>),
+    /// The request has come from what we think is a real person, so we return a minimal HTML page
+    /// that immediately refreshes to the post, with a visible link as a fallback.
+    DirectLink(Box<PostRedirect>),
     /// The post is account gated and requires an authenticated account to view, so we return an
     /// HTML page with a different embed card informing people of such.
     AccountGatedEmbed(Box<EmbedAccountGated>),
+    /// The post has text but no images, so we return an HTML page with an embed card showing just
+    /// the text over a placeholder image.
+    TextOnlyEmbed(Box<TextEmbed>),
+    /// The thread around the post was asked for, so we return an HTML page with an embed card
+    /// showing the conversation in its description.
+    ThreadEmbed(Box<ThreadEmbed>),
+    /// The request has come from Iframely asking for JSON, so we return the embed data in its
+    /// format rather than an HTML page.
+    Iframely(Box<IframelyResponse>),
+    /// The embed page has already been rendered to HTML, either just now or by an earlier request
+    /// for the same post that's still in the template cache.
+    Rendered(Bytes),
+}
+
+impl EmbedRouter {
+    /// Renders the HTML page for embeds that are worth caching. Direct links are cheap to render
+    /// and JSON responses aren't pages, so those return `None`.
+    fn render_cacheable(&self) -> Option<askama::Result<String>> {
+        match self {
+            EmbedRouter::Embed(embed, locale) => Some(embed.render_localized(*locale)),
+            EmbedRouter::AccountGatedEmbed(embed) => Some(embed.render()),
+            EmbedRouter::TextOnlyEmbed(embed) => Some(embed.render()),
+            EmbedRouter::ThreadEmbed(embed) => Some(embed.render()),
+            EmbedRouter::DirectLink(_) | EmbedRouter::Iframely(_) | EmbedRouter::Rendered(_) => {
+                None
+            }
+        }
+    }
 }
 
 impl IntoResponse for EmbedRouter {
     fn into_response(self) -> Response {
         match self {
-            EmbedRouter::Embed(embed) => embed.into_response(),
+            EmbedRouter::Embed(embed, locale) => match embed.render_localized(locale) {
+                Ok(html) => Html(html).into_response(),
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            },
             EmbedRouter::DirectLink(redirect) => redirect.into_response(),
             EmbedRouter::AccountGatedEmbed(embed) => embed.into_response(),
+            EmbedRouter::TextOnlyEmbed(embed) => embed.into_response(),
+            EmbedRouter::ThreadEmbed(embed) => embed.into_response(),
+            EmbedRouter::Iframely(response) => Json(response).into_response(),
+            EmbedRouter::Rendered(html) => Html(html).into_response(),
         }
     }
 }
@@ -259,74 +1208,1077 @@ impl IntoResponse for EmbedRouter {
 /// Telegram.
 async fn embed_image(
     Path((identifier, post_id)): Path<(String, String)>,
+    params: Query<EmbedParams>,
     RequireEmbed(embed_agent): RequireEmbed,
-    State(state): State<AppState>,
+    HostState(state): HostState,
+    host: Option<Host>,
 ) -> Result<EmbedRouter, EmbedError> {
     let post_url = format!("https://bsky.app/profile/{identifier}/post/{post_id}");
 
+    // The links in the embed all point at the base URL, so if it doesn't match the host the request
+    // came in on the images will most likely be broken.
+    if let Some(Host(host)) = &host {
+        if !base_url_matches_host(&state.base_url, host) {
+            warn!(
+                "Request for {host} is using the base URL {}, check that it's configured correctly",
+                state.base_url
+            );
+        }
+    }
+
     // There was no User-Agent header that is associated with embedded, so to speed things up we
-    // just immediately return a 403 Redirect rather than presenting any HTML.
-    if embed_agent.is_none() {
-        let direct_link = EmbedRouter::DirectLink(Redirect::temporary(&post_url));
+    // just immediately send them to the post rather than looking anything up.
+    let Some(embed_agent) = embed_agent else {
+        let direct_link = EmbedRouter::DirectLink(Box::new(PostRedirect { post_url }));
         return Ok(direct_link);
+    };
+
+    // Matrix link previews are generated server side by Synapse, which only looks at OpenGraph tags
+    // so we need to add a few extra ones for the card to show up properly.
+    let matrix_preview =
+        params.via.as_deref() == Some("matrix") || user_agent::is_matrix_agent(&embed_agent);
+
+    if state.dry_run {
+        let embed = dry_run::stub_embed(&state.base_url, post_url, matrix_preview);
+        return Ok(EmbedRouter::TextOnlyEmbed(Box::new(embed)));
     }
 
-    let response = state
-        .agent
-        .api
-        .com
-        .atproto
-        .identity
-        .resolve_handle(resolve_handle::Parameters {
-            handle: identifier.to_owned(),
-        })
+    let aturi = get_aturi(&identifier, &post_id, &state)
         .await
-        .map_err(|_| EmbedError::ResolveHandleError)?;
+        .map_err(|err| {
+            error!("Failed to render embed for post {post_url}: {err}");
+            err
+        })?;
 
-    let aturi = format!("at://{}/app.bsky.feed.post/{post_id}", response.did);
+    // Iframely's JSON responses aren't HTML, so they always skip the template cache.
+    let wants_json =
+        params.format.as_deref() == Some("json") && user_agent::is_iframely_agent(&embed_agent);
 
-    let view = get_post(&aturi, &state).await?;
+    let cache_key = format!(
+        "{}#{aturi}#{}#{matrix_preview}#{}",
+        state.base_url,
+        params.lang.as_deref().unwrap_or_default(),
+        params.thread.unwrap_or_default(),
+    );
+
+    if !wants_json {
+        if let Some(html) = state.template_cache.get(&cache_key) {
+            // The view still counts, but the cached page keeps the count from when it was rendered.
+            if let Some(views) = &state.views {
+                views.increment(&aturi).await;
+            }
+            return Ok(EmbedRouter::Rendered(html));
+        }
+    }
+
+    let embed = build_embed(
+        aturi.to_owned(),
+        post_url,
+        &params,
+        matrix_preview,
+        wants_json,
+        true,
+        &state,
+    )
+    .await
+    .map_err(|err| {
+        error!("Failed to render embed for post {aturi}: {err}");
+        err
+    })?;
+    match embed.render_cacheable() {
+        Some(html) => {
+            let html = Bytes::from(html.map_err(|err| {
+                error!("Failed to render embed for post {aturi}: {err}");
+                err
+            })?);
+            state.template_cache.insert(cache_key, html.clone());
+            Ok(EmbedRouter::Rendered(html))
+        }
+        None => Ok(embed),
+    }
+}
+
+/// Utility function that fetches a post and picks the embed to show for it. Only the embed page
+/// itself should set `count_view`, the other pages built from the embed leave the count alone.
+async fn build_embed(
+    aturi: String,
+    post_url: String,
+    params: &EmbedParams,
+    matrix_preview: bool,
+    wants_json: bool,
+    count_view: bool,
+    state: &AppState,
+) -> Result<EmbedRouter, EmbedError> {
+    let view = get_post(&aturi, state).await?;
 
     // If the account has a label set to require only authenticated accounts we respect it and
     // return a different embed card informing people of such.
-    if let Some(labels) = &view.author.labels {
-        if labels
-            .par_iter()
-            .any(|label| label.val == "!no-unauthenticated")
-        {
-            let embed = EmbedRouter::AccountGatedEmbed(Box::new(EmbedAccountGated {
-                profile: view.author.to_owned(),
-                base_url: state.base_url.to_owned(),
-                post_url,
-            }));
-            return Ok(embed);
+    if requires_authentication(&view.author) {
+        let embed = EmbedRouter::AccountGatedEmbed(Box::new(EmbedAccountGated {
+            profile: view.author.to_owned(),
+            base_url: state.base_url.to_owned(),
+            post_url,
+            matrix_preview,
+        }));
+        return Ok(embed);
+    }
+
+    let record = match &view.record {
+        Record::AppBskyFeedPost(record) => record.to_owned(),
+        _ => return Err(EmbedError::UnimplementedRecordHandler),
+    };
+
+    let view_count = match &state.views {
+        Some(views) if count_view => views.increment(&aturi).await,
+        Some(views) => views.get(&aturi).await,
+        None => None,
+    };
+
+    let content_warning = content_warning(&view);
+
+    if wants_json {
+        let response = IframelyResponse::new(
+            &view,
+            &record,
+            post_url,
+            &state.base_url,
+            state.processing.output_format.to_mime_type(),
+        );
+        return Ok(EmbedRouter::Iframely(Box::new(response)));
+    }
+
+    if params.thread == Some(true) {
+        let image_url = match embed::post_images(&view) {
+            Some(_) => api::combined_image_url(&state.base_url, &aturi),
+            None => format!("{}/text-only.png", state.base_url),
+        };
+        let thread = get_thread_description(&aturi, &record, state).await?;
+
+        let embed = EmbedRouter::ThreadEmbed(Box::new(ThreadEmbed {
+            profile: view.author.to_owned(),
+            post_url,
+            record,
+            matrix_preview,
+            view_count,
+            content_warning,
+            image_url,
+            thread,
+        }));
+        return Ok(embed);
+    }
+
+    // Posts without any images still get a useful embed with their text, just with a placeholder
+    // image rather than a combined thumbnail.
+    if embed::post_images(&view).is_none() {
+        let embed = EmbedRouter::TextOnlyEmbed(Box::new(TextEmbed {
+            profile: view.author.to_owned(),
+            base_url: state.base_url.to_owned(),
+            post_url,
+            record,
+            matrix_preview,
+            view_count,
+            content_warning,
+        }));
+        return Ok(embed);
+    }
+
+    let locale = params
+        .lang
+        .as_deref()
+        .and_then(Locale::from_tag)
+        .filter(|locale| locale.is_compatible(record.langs.as_ref()));
+
+    let embed = EmbedRouter::Embed(
+        Box::new(ImageEmbed {
+            profile: view.author.to_owned(),
+            base_url: state.base_url.to_owned(),
+            aturi,
+            post_url,
+            record,
+            matrix_preview,
+            view_count,
+            content_warning,
+        }),
+        locale,
+    );
+
+    Ok(embed)
+}
+
+/// Utility function that fetches the thread around a post and describes the conversation, see
+/// [thread::thread_description].
+async fn get_thread_description(
+    aturi: &str,
+    record: &atrium_api::app::bsky::feed::post::Record,
+    state: &AppState,
+) -> Result<String, EmbedError> {
+    let thread = get_thread(aturi, state).await?;
+
+    // The root is fetched separately when the thread is too deep for it to be one of the ancestors,
+    // it's only there for context so the embed goes ahead without it if it's gone.
+    let root = match &record.reply {
+        Some(reply) if !thread::shows_root(&thread, &reply.root.uri) => {
+            get_post(&reply.root.uri, state).await.ok()
         }
+        _ => None,
+    };
+
+    Ok(thread::thread_description(&thread, root.as_ref()))
+}
+
+/// Utility function to get a post along with its nearest ancestors and first replies.
+async fn get_thread(aturi: &str, state: &AppState) -> Result<ThreadViewPost, EmbedError> {
+    let response = retry_with_backoff(
+        || {
+            state
+                .agent
+                .api
+                .app
+                .bsky
+                .feed
+                .get_post_thread(get_post_thread::Parameters {
+                    depth: Some(1),
+                    parent_height: Some(thread::MAX_ANCESTORS as i32),
+                    uri: aturi.to_owned(),
+                })
+        },
+        API_MAX_RETRIES,
+    )
+    .await?;
+
+    match response.thread {
+        OutputThreadEnum::AppBskyFeedDefsThreadViewPost(thread) => Ok(*thread),
+        OutputThreadEnum::AppBskyFeedDefsBlockedPost(_) => Err(EmbedError::PostBlocked),
+        OutputThreadEnum::AppBskyFeedDefsNotFoundPost(_) => Err(EmbedError::PostDeleted),
     }
+}
+
+/// Handler that takes the same path as a bluesky post and returns an AMP version of its embed page,
+/// only available for posts with images.
+async fn embed_amp(
+    Path((identifier, post_id)): Path<(String, String)>,
+    params: Query<EmbedParams>,
+    HostState(state): HostState,
+) -> Result<Response, EmbedError> {
+    let post_url = format!("https://bsky.app/profile/{identifier}/post/{post_id}");
+    let aturi = get_aturi(&identifier, &post_id, &state).await?;
+
+    let embed = match build_embed(aturi, post_url, &params, true, false, false, &state).await? {
+        EmbedRouter::Embed(embed, _) => embed,
+        EmbedRouter::AccountGatedEmbed(_) => return Err(EmbedError::AccountGated),
+        _ => return Err(EmbedError::PostHasNoImages),
+    };
+
+    let html = AmpImageEmbed { embed: &embed }.render()?;
+
+    // AMP pages have to load the AMP runtime from its CDN, so they get their own policy allowing
+    // that in place of the one the other embed pages get.
+    let policy = format!(
+        "default-src 'none'; \
+         script-src https://cdn.ampproject.org; \
+         img-src 'self' {} https://cdn.bsky.app https://av-cdn.bsky.app; \
+         style-src 'unsafe-inline'; \
+         base-uri 'none'",
+        state.base_url
+    );
+
+    let mut response = Html(html).into_response();
+    if let Ok(policy) = HeaderValue::from_str(&policy) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_SECURITY_POLICY, policy);
+    }
+
+    Ok(response)
+}
+
+/// Handler that takes the same path as a bluesky post and returns a page previewing what its embed
+/// card will look like, built from the same data as the embed page itself.
+async fn embed_preview(
+    Path((identifier, post_id)): Path<(String, String)>,
+    params: Query<EmbedParams>,
+    HostState(state): HostState,
+) -> Result<EmbedPreview, EmbedError> {
+    let post_url = format!("https://bsky.app/profile/{identifier}/post/{post_id}");
+    let aturi = get_aturi(&identifier, &post_id, &state).await?;
+    let embed = build_embed(
+        aturi,
+        post_url.to_owned(),
+        &params,
+        false,
+        false,
+        false,
+        &state,
+    )
+    .await?;
+    let (title, description, image_url) = preview_card(&embed, &state)?;
+
+    let embed_html = embed
+        .render_cacheable()
+        .ok_or(EmbedError::UnimplementedRecordHandler)??;
 
-    let record = match view.record {
-        Record::AppBskyFeedPost(record) => record,
+    Ok(EmbedPreview {
+        post_url,
+        title,
+        description,
+        image_url,
+        embed_html,
+    })
+}
+
+/// Handler that takes the same path as a bluesky post and returns just its embed card, as a page
+/// that can be put in an `<iframe>` on other sites by the script embed.
+async fn embed_frame(
+    Path((identifier, post_id)): Path<(String, String)>,
+    params: Query<EmbedParams>,
+    HostState(state): HostState,
+) -> Result<(Extension<middleware::Framable>, EmbedFrame), EmbedError> {
+    let post_url = format!("https://bsky.app/profile/{identifier}/post/{post_id}");
+    let aturi = get_aturi(&identifier, &post_id, &state).await?;
+    let embed = build_embed(
+        aturi,
+        post_url.to_owned(),
+        &params,
+        false,
+        false,
+        false,
+        &state,
+    )
+    .await?;
+    let (title, description, image_url) = preview_card(&embed, &state)?;
+
+    let frame = EmbedFrame {
+        post_url,
+        title,
+        description,
+        image_url,
+    };
+
+    Ok((Extension(middleware::Framable), frame))
+}
+
+/// Handler for the share page of a post, a share sheet with a preview of the post's embed card and
+/// a QR code for its link that can be embedded in other apps.
+async fn share_page(
+    Path((identifier, post_id)): Path<(String, String)>,
+    params: Query<EmbedParams>,
+    HostState(state): HostState,
+) -> Result<SharePage, EmbedError> {
+    let post_url = format!("https://bsky.app/profile/{identifier}/post/{post_id}");
+    let aturi = get_aturi(&identifier, &post_id, &state).await?;
+    let embed = build_embed(aturi, post_url, &params, false, false, false, &state).await?;
+    let (title, description, image_url) = preview_card(&embed, &state)?;
+
+    let identifier = utf8_percent_encode(&identifier, IDENTIFIER_ENCODE_SET);
+    let post_id = utf8_percent_encode(&post_id, NON_ALPHANUMERIC);
+    let share_url = format!("{}/profile/{identifier}/post/{post_id}", state.base_url);
+
+    let svg = QrCode::new(&share_url)?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    // The XML prolog isn't needed for an SVG inlined into HTML.
+    let qr_code = match svg.find("<svg") {
+        Some(start) => svg[start..].to_owned(),
+        None => svg,
+    };
+
+    Ok(SharePage {
+        base_url: state.base_url,
+        share_url,
+        title,
+        description,
+        image_url,
+        qr_code,
+    })
+}
+
+/// Utility function that picks out the title, description and image an embed's card shows, for
+/// the pages that mock one up.
+fn preview_card(
+    embed: &EmbedRouter,
+    state: &AppState,
+) -> Result<(String, String, String), EmbedError> {
+    let card = match embed {
+        EmbedRouter::Embed(embed, _) => (
+            embed_title(&embed.profile),
+            preview_description(&embed.record.text, &embed.content_warning),
+            embed.image_url(),
+        ),
+        EmbedRouter::TextOnlyEmbed(embed) => (
+            embed_title(&embed.profile),
+            preview_description(&embed.record.text, &embed.content_warning),
+            embed.image_url(),
+        ),
+        EmbedRouter::ThreadEmbed(embed) => (
+            embed_title(&embed.profile),
+            preview_description(&embed.thread, &embed.content_warning),
+            embed.image_url.to_owned(),
+        ),
+        EmbedRouter::AccountGatedEmbed(embed) => (
+            embed_title(&embed.profile),
+            "This post requires an account to view!".to_owned(),
+            format!("{}/gated.png", state.base_url),
+        ),
         _ => return Err(EmbedError::UnimplementedRecordHandler),
     };
 
-    let embed = EmbedRouter::Embed(Box::new(ImageEmbed {
-        profile: view.author.to_owned(),
-        base_url: state.base_url.to_owned(),
+    Ok(card)
+}
+
+/// Utility function for the description an embed card shows, matching the one in the OpenGraph
+/// tags.
+fn preview_description(text: &str, content_warning: &Option<String>) -> String {
+    match content_warning {
+        Some(label) => format!("Content Warning ({label}): click to reveal"),
+        None => text.to_owned(),
+    }
+}
+
+/// Handler that takes the same path as a bluesky post and returns just the meta tags its embed page
+/// would have, as an HTML fragment without the rest of the document.
+async fn opengraph_tags(
+    Path((identifier, post_id)): Path<(String, String)>,
+    HostState(state): HostState,
+) -> Result<OpenGraphTags, EmbedError> {
+    let ResolvedPost {
         aturi,
         post_url,
+        view,
         record,
-    }));
+    } = resolve_post(&identifier, &post_id, &state).await?;
 
-    Ok(embed)
+    let image_url = match embed::post_images(&view) {
+        Some(_) => api::combined_image_url(&state.base_url, &aturi),
+        None => format!("{}/text-only.png", state.base_url),
+    };
+
+    let content_warning = content_warning(&view);
+
+    let view_count = match &state.views {
+        Some(views) => views.get(&aturi).await,
+        None => None,
+    };
+
+    Ok(OpenGraphTags {
+        profile: view.author,
+        post_url,
+        record,
+        // Anything reading the fragment is after the OpenGraph tags, so always include all of them.
+        matrix_preview: true,
+        view_count,
+        content_warning,
+        image_url,
+    })
+}
+
+/// Handler that takes the same path as a bluesky post and returns only the Twitter card meta tags
+/// for it, with no other HTML around them.
+async fn twitter_card(
+    Path((identifier, post_id)): Path<(String, String)>,
+    HostState(state): HostState,
+) -> Result<TwitterCard, EmbedError> {
+    let ResolvedPost {
+        aturi,
+        view,
+        record,
+        ..
+    } = resolve_post(&identifier, &post_id, &state).await?;
+
+    let (card, image_url) = match embed::post_images(&view) {
+        Some(_) => (
+            "summary_large_image",
+            api::combined_image_url(&state.base_url, &aturi),
+        ),
+        None => ("summary", format!("{}/text-only.png", state.base_url)),
+    };
+
+    Ok(TwitterCard {
+        card,
+        title: api::embed_title(&view.author),
+        description: preview_description(&record.text, &content_warning(&view)),
+        image_url,
+    })
+}
+
+/// Utility function to check if an account has asked for its posts to only be shown to people who
+/// are signed in, through the `!no-unauthenticated` label.
+fn requires_authentication(author: &ProfileViewBasic) -> bool {
+    author
+        .labels
+        .as_ref()
+        .map(|labels| {
+            labels
+                .par_iter()
+                .any(|label| label.val == "!no-unauthenticated")
+        })
+        .unwrap_or(false)
+}
+
+/// Labels that put a content warning over a post's images, whether they're on the post itself or
+/// the account that made it.
+const CONTENT_WARNING_LABELS: [&str; 4] = ["!warn", "porn", "nsfw", "graphic-media"];
+
+/// Utility function to find the content warning label on a post or its author, if there is one.
+fn content_warning(view: &PostView) -> Option<String> {
+    view.labels
+        .iter()
+        .chain(view.author.labels.iter())
+        .flatten()
+        .find(|label| CONTENT_WARNING_LABELS.contains(&label.val.as_str()))
+        .map(|label| label.val.to_owned())
+}
+
+/// Handler that takes the same path as a bluesky post and returns the data used to build its embed
+/// card as JSON, for people who want to render their own embeds. No images are composited here,
+/// the returned `image_url` points to the rendering endpoint instead.
+async fn embed_card(
+    Path((identifier, post_id)): Path<(String, String)>,
+    HostState(state): HostState,
+) -> Result<Json<EmbedCard>, ApiError> {
+    let ResolvedPost {
+        aturi,
+        view,
+        record,
+        ..
+    } = resolve_post(&identifier, &post_id, &state).await?;
+
+    let mut card = EmbedCard::new(&view, &record, &state.base_url);
+    if let Some(views) = &state.views {
+        card.view_count = views.get(&aturi).await;
+    }
+
+    Ok(Json(card))
+}
+
+/// The most posts `getPosts` will return in one request.
+const BATCH_EMBED_MAX_POSTS: usize = 25;
+
+/// How many posts a batch embed request returns if it doesn't give a limit.
+const BATCH_EMBED_DEFAULT_LIMIT: usize = 10;
+
+/// The body of a batch embed request.
+#[derive(Deserialize)]
+pub struct BatchEmbedRequest {
+    /// The ATUris of the posts to get embed cards for.
+    pub uris: Vec<String>,
+    /// How many of the posts to return, capped at [BATCH_EMBED_MAX_POSTS].
+    pub limit: Option<usize>,
+}
+
+/// Handler that returns the embed cards for several posts at once, for clients pre-fetching the
+/// embeds of a page of links. The posts are fetched in a single request, and ones that don't exist
+/// or are from accounts that need to be signed in to see are left out.
+async fn batch_embed(
+    HostState(state): HostState,
+    Json(body): Json<BatchEmbedRequest>,
+) -> Result<Json<Vec<EmbedCard>>, ApiError> {
+    let limit = body
+        .limit
+        .unwrap_or(BATCH_EMBED_DEFAULT_LIMIT)
+        .min(BATCH_EMBED_MAX_POSTS);
+    let uris: Vec<_> = body.uris.into_iter().take(limit).collect();
+    if uris.is_empty() {
+        return Ok(Json(Vec::new()));
+    }
+
+    // One malformed ATUri fails the whole getPosts request, so they're checked before sending.
+    if let Some(uri) = uris.iter().find(|uri| !is_post_aturi(uri)) {
+        return Err(EmbedError::InvalidPostUri(uri.to_owned()).into());
+    }
+
+    let response = retry_with_backoff(
+        || {
+            state
+                .agent
+                .api
+                .app
+                .bsky
+                .feed
+                .get_posts(get_posts::Parameters { uris: uris.clone() })
+        },
+        API_MAX_RETRIES,
+    )
+    .await
+    .map_err(EmbedError::from)?;
+
+    let cards = response
+        .posts
+        .iter()
+        .filter(|view| !requires_authentication(&view.author))
+        .filter_map(|view| match &view.record {
+            Record::AppBskyFeedPost(record) => Some(EmbedCard::new(view, record, &state.base_url)),
+            _ => None,
+        })
+        .collect();
+
+    Ok(Json(cards))
+}
+
+/// Utility function that checks an ATUri points at a post, like
+/// `at://videah.net/app.bsky.feed.post/3kt5kxrmbx22b`.
+fn is_post_aturi(uri: &str) -> bool {
+    let Some(path) = uri.strip_prefix("at://") else {
+        return false;
+    };
+
+    match path.split('/').collect::<Vec<_>>()[..] {
+        [authority, "app.bsky.feed.post", rkey] => {
+            !authority.is_empty()
+                && !rkey.is_empty()
+                && (authority.starts_with("did:") || authority.contains('.'))
+        }
+        _ => false,
+    }
+}
+
+/// Handler that takes the same path as a bluesky post and returns a Slack message attachment for
+/// it, for Slack apps answering `link_shared` events with an unfurl.
+async fn slack_card(
+    Path((identifier, post_id)): Path<(String, String)>,
+    HostState(state): HostState,
+) -> Result<Json<SlackAttachment>, ApiError> {
+    let ResolvedPost {
+        post_url,
+        view,
+        record,
+        ..
+    } = resolve_post(&identifier, &post_id, &state).await?;
+
+    let attachment = SlackAttachment::new(&view, &record, post_url, &state.base_url);
+    Ok(Json(attachment))
+}
+
+/// Handler that takes the same path as a bluesky post and returns a schema.org `Article` for it as
+/// JSON-LD structured data.
+async fn embed_json_ld(
+    Path((identifier, post_id)): Path<(String, String)>,
+    HostState(state): HostState,
+) -> Result<impl IntoResponse, ApiError> {
+    let ResolvedPost {
+        aturi,
+        post_url,
+        view,
+        record,
+    } = resolve_post(&identifier, &post_id, &state).await?;
+
+    let image = embed::post_images(&view).map(|_| api::combined_image_url(&state.base_url, &aturi));
+    let article = JsonLdArticle::new(
+        &view.author,
+        &record,
+        &content_warning(&view),
+        post_url,
+        image,
+    );
+    Ok((
+        [(header::CONTENT_TYPE, "application/ld+json")],
+        Json(article),
+    ))
+}
+
+/// Handler that returns a JavaScript snippet for embedding a post on external pages with a
+/// `<script>` tag, similar to how Twitter and YouTube embeds work.
+async fn embed_script(
+    Path((identifier, post_id)): Path<(String, String)>,
+    HostState(state): HostState,
+) -> ScriptEmbed {
+    let identifier = utf8_percent_encode(&identifier, NON_ALPHANUMERIC);
+    let post_id = utf8_percent_encode(&post_id, NON_ALPHANUMERIC);
+
+    ScriptEmbed {
+        embed_url: format!(
+            "{}/profile/{identifier}/post/{post_id}/frame",
+            state.base_url
+        ),
+    }
+}
+
+/// Handler that upgrades to a WebSocket and pushes a post's like, repost and reply counts as JSON
+/// messages whenever they change, so embeds of a post that's taking off can keep up with it.
+///
+/// The counts start from the post as it is when the connection opens, and go up as new likes,
+/// reposts and replies come in over the firehose. Nothing is polled, so unlikes and deletions
+/// aren't reflected until the page is reloaded, as the firehose doesn't say what a deleted record
+/// referred to.
+async fn post_updates(
+    Path((identifier, post_id)): Path<(String, String)>,
+    ws: WebSocketUpgrade,
+    HostState(state): HostState,
+) -> Result<Response, EmbedError> {
+    let permit = state
+        .post_updates
+        .connect()
+        .ok_or(EmbedError::TooManyConnections)?;
+
+    // Fetching the post before upgrading means a bad link gets a normal error response.
+    let aturi = get_aturi(&identifier, &post_id, &state).await?;
+    let view = get_post(&aturi, &state).await?;
+    let counts = state
+        .post_updates
+        .subscribe(&aturi, PostCounts::from(&view));
+
+    Ok(ws.on_upgrade(move |socket| async move {
+        stream_post_counts(socket, counts).await;
+        state.post_updates.unsubscribe(&aturi);
+        drop(permit);
+    }))
+}
+
+/// Utility function that sends a post's counts down the socket straight away and then whenever
+/// they change, until the client disconnects.
+async fn stream_post_counts(mut socket: WebSocket, mut counts: CountsReceiver) {
+    loop {
+        let json = serde_json::to_string(&*counts.borrow_and_update());
+        if let Ok(json) = json {
+            if socket.send(Message::Text(json)).await.is_err() {
+                return;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                changed = counts.changed() => match changed {
+                    Ok(()) => break,
+                    Err(_) => {
+                        let _ = socket.send(Message::Close(None)).await;
+                        return;
+                    }
+                },
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                },
+            }
+        }
+    }
+}
+
+/// Parameters passed to the oEmbed endpoint.
+#[derive(Deserialize)]
+pub struct OEmbedParams {
+    /// A link to a post, either on bluesky or this instance.
+    pub url: String,
+}
+
+/// Handler for oEmbed consumers, returning an oEmbed object for the post a link points to. Posts
+/// with images are returned as a `photo` of their combined thumbnail, which gets rendered here to
+/// find out its size.
+async fn oembed(
+    params: Query<OEmbedParams>,
+    HostState(state): HostState,
+) -> Result<Json<OEmbedResponse>, ApiError> {
+    let url =
+        reqwest::Url::parse(&params.url).map_err(|_| ApiError::from(EmbedError::InvalidPostUrl))?;
+    let segments: Vec<_> = url
+        .path_segments()
+        .map(|segments| segments.collect())
+        .unwrap_or_default();
+
+    let ["profile", identifier, "post", post_id] = segments.as_slice() else {
+        return Err(EmbedError::InvalidPostUrl.into());
+    };
+
+    let ResolvedPost { aturi, view, .. } = resolve_post(identifier, post_id, &state).await?;
+
+    let thumbnail = match embed::post_images(&view) {
+        Some(_) => Some(render_thumbnail(&aturi, &state).await?.dimensions()),
+        None => None,
+    };
+
+    Ok(Json(OEmbedResponse::new(&view, &state.base_url, thumbnail)))
+}
+
+/// Parameters passed to the search endpoint.
+#[derive(Deserialize)]
+pub struct SearchParams {
+    pub q: String,
 }
 
-/// Basic handler to redirect to the main website from the root path.
+/// The number of search results shown on the search page.
+const SEARCH_RESULT_LIMIT: i32 = 5;
+
+/// Handler that searches bluesky posts and returns an HTML page with an embed card for the most
+/// popular result, along with links to the embeds of the other top results.
+async fn search(
+    params: Query<SearchParams>,
+    HostState(state): HostState,
+) -> Result<SearchResults, EmbedError> {
+    let response = retry_with_backoff(
+        || {
+            state
+                .agent
+                .api
+                .app
+                .bsky
+                .feed
+                .search_posts(search_posts::Parameters {
+                    cursor: None,
+                    limit: Some(SEARCH_RESULT_LIMIT),
+                    q: params.q.to_owned(),
+                })
+        },
+        API_MAX_RETRIES,
+    )
+    .await?;
+
+    let mut results: Vec<_> = response
+        .posts
+        .iter()
+        .take(SEARCH_RESULT_LIMIT as usize)
+        .filter(|view| !requires_authentication(&view.author))
+        .filter_map(|view| {
+            SearchResult::new(view, &state.base_url, content_warning(view).as_deref())
+        })
+        .collect();
+
+    // The most liked post gets used for the embed card itself.
+    let top_index = results
+        .iter()
+        .enumerate()
+        .max_by_key(|(_, result)| result.like_count)
+        .map(|(index, _)| index)
+        .ok_or(EmbedError::NoSearchResults)?;
+    let top = results.remove(top_index);
+
+    Ok(SearchResults {
+        query: params.q.to_owned(),
+        top,
+        others: results,
+    })
+}
+
+/// Handler for the developer page showing the embed previews of the posts in
+/// `VXSKY_EMBED_TEST_POSTS`. Links that aren't to a bluesky post are left out.
+async fn embed_test(HostState(state): HostState) -> EmbedTest {
+    let posts = state
+        .embed_test_posts
+        .iter()
+        .filter_map(|post_url| {
+            let path = reqwest::Url::parse(post_url).ok()?.path().to_owned();
+            let segments: Vec<_> = path.trim_matches('/').split('/').collect();
+            let ["profile", _, "post", _] = segments.as_slice() else {
+                return None;
+            };
+
+            Some(EmbedTestPost {
+                post_url: post_url.to_owned(),
+                preview_url: format!("{}{path}/preview", state.base_url),
+            })
+        })
+        .collect();
+
+    EmbedTest { posts }
+}
+
+/// Basic handler to redirect to the main website from the root and `/about` paths.
 async fn index_redirect() -> Redirect {
     Redirect::temporary("https://bsky.app/profile/vxsky.app")
 }
 
+/// Fallback handler for paths that don't match any route. API clients get a JSON error, either
+/// because they asked for JSON or the path looks like one of the `.json` endpoints, and everyone
+/// else gets an HTML page explaining what vxsky links look like.
+async fn handle_404(uri: Uri, headers: HeaderMap) -> Response {
+    let path = uri.path().to_owned();
+
+    if path.ends_with(".json") || middleware::prefers_json(&headers) {
+        let body = ErrorBody {
+            error: format!("There's nothing at {path}"),
+            code: StatusCode::NOT_FOUND.as_u16(),
+        };
+        return (StatusCode::NOT_FOUND, Json(body)).into_response();
+    }
+
+    (StatusCode::NOT_FOUND, NotFoundPage { path }).into_response()
+}
+
+/// Basic health check handler for uptime monitors and liveness probes, served at both `/health` and
+/// the Kubernetes style `/healthz`.
+async fn health() -> &'static str {
+    "OK"
+}
+
+/// Handler that reports which build of vxsky is running.
+async fn version() -> Json<VersionInfo> {
+    Json(VersionInfo::CURRENT)
+}
+
+/// Handler that reports how many requests have been served and how long they've been taking.
+async fn status(HostState(state): HostState) -> Json<StatusInfo> {
+    Json(StatusInfo {
+        requests_served: state.stats.requests_served(),
+        latency_p50_ms: state.stats.latency_percentile(0.5),
+        latency_p90_ms: state.stats.latency_percentile(0.9),
+        latency_p99_ms: state.stats.latency_percentile(0.99),
+    })
+}
+
+/// Handler that exposes the same statistics as `/status` for Prometheus to scrape.
+async fn metrics(HostState(state): HostState) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.stats.prometheus(),
+    )
+}
+
+/// Handler that shows how the request's `User-Agent` is classified, using the same rules as the
+/// [RequireEmbed] extractor.
+async fn bot_detection(headers: HeaderMap) -> Json<BotDetection> {
+    let ua = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let matched_rule = user_agent::matched_rule(&ua);
+
+    Json(BotDetection {
+        is_embed_bot: matched_rule.is_some(),
+        ua,
+        matched_rule,
+    })
+}
+
+/// Utility function that checks a request to an admin endpoint has the `VXSKY_ADMIN_TOKEN` as its
+/// bearer token. Admin endpoints act like they don't exist if no token has been configured.
+fn require_admin(headers: &HeaderMap, state: &AppState) -> Result<(), EmbedError> {
+    let expected = state
+        .admin_token
+        .as_deref()
+        .ok_or(EmbedError::AdminDisabled)?;
+
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(EmbedError::Unauthorized)?;
+
+    // Comparing BLAKE3 hashes is constant time, so the token can't be guessed from how long the
+    // comparison takes.
+    match blake3::hash(token.as_bytes()) == blake3::hash(expected.as_bytes()) {
+        true => Ok(()),
+        false => Err(EmbedError::Unauthorized),
+    }
+}
+
+/// Admin handler that lists the user agents recognized as embed bots, so operators can check
+/// which bots get embeds without reading through the source.
+async fn list_user_agents(
+    headers: HeaderMap,
+    HostState(state): HostState,
+) -> Result<Json<Vec<&'static str>>, ApiError> {
+    require_admin(&headers, &state)?;
+    Ok(Json(user_agent::known_embed_agents()))
+}
+
+/// The session tokens accepted by the `PATCH /session` admin endpoint.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInjection {
+    pub access_jwt: String,
+    pub refresh_jwt: String,
+    pub did: String,
+    /// The handle of the account, replaced with the real one once the session has been checked.
+    pub handle: Option<String>,
+}
+
+/// The part of a `com.atproto.server.getSession` response needed to check an injected session.
+#[derive(Deserialize)]
+struct SessionCheck {
+    did: String,
+}
+
+/// Utility function that checks session tokens are valid and belong to the expected account before
+/// handing them to the agent. The agent throws away its current session if resuming fails, and
+/// panics if the DID doesn't match, so neither can be left to it.
+async fn check_session(access_jwt: &str, did: &str, state: &AppState) -> Result<(), EmbedError> {
+    let response = state
+        .http_client
+        .get("https://bsky.social/xrpc/com.atproto.server.getSession")
+        .bearer_auth(access_jwt)
+        .send()
+        .await
+        .map_err(|err| EmbedError::InvalidSession(format!("couldn't check the session: {err}")))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        return Err(EmbedError::InvalidSession(format!(
+            "getSession returned {status}"
+        )));
+    }
+
+    let session: SessionCheck = response
+        .bytes()
+        .await
+        .map_err(|err| err.to_string())
+        .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|err| err.to_string()))
+        .map_err(EmbedError::InvalidSession)?;
+    match session.did == did {
+        true => Ok(()),
+        false => Err(EmbedError::InvalidSession(format!(
+            "the tokens belong to {}, not {did}",
+            session.did
+        ))),
+    }
+}
+
+/// Admin handler that replaces the bluesky session with one from the request, for when the
+/// current session has gone stale and logging in again isn't an option without a restart. The
+/// session is checked with the API before it replaces the current one.
+async fn inject_session(
+    headers: HeaderMap,
+    HostState(state): HostState,
+    Json(body): Json<SessionInjection>,
+) -> Result<StatusCode, EmbedError> {
+    require_admin(&headers, &state)?;
+    check_session(&body.access_jwt, &body.did, &state).await?;
+
+    let session = Session {
+        access_jwt: body.access_jwt,
+        handle: body.handle.unwrap_or_else(|| body.did.to_owned()),
+        did: body.did,
+        did_doc: None,
+        email: None,
+        email_confirmed: None,
+        refresh_jwt: body.refresh_jwt,
+    };
+
+    state.agent.resume_session(session).await?;
+    info!("Replaced the bluesky session through the admin endpoint");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Handler for NodeInfo discovery, pointing tools at the NodeInfo document for this instance.
+async fn nodeinfo_discovery(HostState(state): HostState) -> Json<NodeInfoLinks> {
+    Json(NodeInfoLinks {
+        links: vec![NodeInfoLink {
+            rel: api::NODEINFO_SCHEMA,
+            href: format!("{}/nodeinfo/2.0", state.base_url),
+        }],
+    })
+}
+
+/// Handler for the NodeInfo 2.0 document, identifying this instance as vxsky.
+async fn nodeinfo() -> Json<NodeInfo> {
+    Json(NodeInfo::new())
+}
+
 /// Handler to serve the image used for the account gated embed card, where a user must be logged in
 /// to view the contents of a post.
 async fn gated_image() -> impl IntoResponse {
     let image = include_bytes!("../assets/gated.png");
     ([(header::CONTENT_TYPE, "image/png")], image.to_vec())
 }
+
+/// Handler to serve the script behind the buttons on the share page, which has to be its own file
+/// since the content security policy doesn't allow inline scripts.
+async fn share_script() -> impl IntoResponse {
+    let script = include_str!("../assets/share.js");
+    ([(header::CONTENT_TYPE, "text/javascript")], script)
+}
+
+/// Handler to serve the placeholder image used for the embed cards of posts that only have text.
+async fn text_only_image() -> impl IntoResponse {
+    let image = include_bytes!("../assets/text_only.png");
+    ([(header::CONTENT_TYPE, "image/png")], image.to_vec())
+}
@@ -0,0 +1,465 @@
+//! Middleware applied to every route in the application.
+
+use std::{
+    any::Any,
+    net::SocketAddr,
+};
+
+use async_compression::tokio::write::GzipEncoder;
+use axum::{
+    body::{
+        self,
+        Body,
+        HttpBody,
+    },
+    extract::{
+        ConnectInfo,
+        Request,
+        State,
+    },
+    http::{
+        header,
+        HeaderMap,
+        HeaderName,
+        HeaderValue,
+        StatusCode,
+    },
+    middleware::Next,
+    response::{
+        IntoResponse,
+        Response,
+    },
+    Json,
+};
+use futures::StreamExt;
+use log::{
+    error,
+    info,
+    warn,
+};
+use percent_encoding::percent_decode_str;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tower_http::set_header::SetResponseHeaderLayer;
+
+use crate::{
+    api::ErrorBody,
+    state::{
+        request_host,
+        AppState,
+    },
+    templates::ErrorPage,
+};
+
+/// The largest plain text error body that will be rewritten by [handle_error], anything bigger is
+/// passed through untouched.
+const MAX_ERROR_BODY_BYTES: usize = 64 * 1024;
+
+/// How much compressed output can be buffered before compressing more of the image waits for it to
+/// be sent.
+const COMPRESSION_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Query parameters whose values are replaced with a hash when logged.
+const REDACTED_QUERY_PARAMS: [&str; 1] = ["uri"];
+
+/// Logs each request along with the status it was answered with. Query parameters that can contain
+/// user content, like the ATUri passed to the rendering endpoints, are replaced with a BLAKE3 hash
+/// so requests for the same post can still be matched up in the logs.
+pub async fn log_request(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    state.stats.record_request();
+
+    let method = request.method().to_owned();
+    let path = request.uri().path().to_owned();
+    let query = request.uri().query().map(redact_query);
+
+    let response = next.run(request).await;
+
+    let status = response.status();
+    match query {
+        Some(query) => info!("{method} {path}?{query} {status}"),
+        None => info!("{method} {path} {status}"),
+    }
+
+    response
+}
+
+/// Records how long each request took to handle in the latency histogram, which is reported by the
+/// `/status` and `/metrics` endpoints.
+pub async fn record_latency(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = std::time::Instant::now();
+    let response = next.run(request).await;
+    state.stats.record_latency(start.elapsed());
+
+    response
+}
+
+/// Writes a line in the Apache Combined Log Format to the access log for every request to a post's
+/// embed page, if an access log has been configured.
+pub async fn access_log(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(access_log) = &state.access_log else {
+        return next.run(request).await;
+    };
+
+    let path = request.uri().path();
+    if !(path.starts_with("/profile/") && path.contains("/post/")) {
+        return next.run(request).await;
+    }
+
+    let remote = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_else(|| "-".to_owned());
+    let request_line = format!(
+        "{} {} {:?}",
+        request.method(),
+        request.uri(),
+        request.version()
+    );
+    let referer = header_or_dash(request.headers(), header::REFERER);
+    let user_agent = header_or_dash(request.headers(), header::USER_AGENT);
+    let time = chrono::Utc::now().format("%d/%b/%Y:%H:%M:%S %z");
+
+    let response = next.run(request).await;
+
+    // Not every response sets Content-Length, but the body usually knows its own size.
+    let bytes = response
+        .body()
+        .size_hint()
+        .exact()
+        .map_or_else(|| "-".to_owned(), |size| size.to_string());
+    let line = format!(
+        "{remote} - - [{time}] \"{request_line}\" {} {bytes} \"{referer}\" \"{user_agent}\"",
+        response.status().as_u16()
+    );
+    access_log.write(&line).await;
+
+    response
+}
+
+/// Gets a header as a string for the access log, escaping quotes so it can't break out of the
+/// quoted field. Missing headers are shown as `-` like Apache does.
+fn header_or_dash(headers: &HeaderMap, name: HeaderName) -> String {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.replace('"', "\\\""))
+        .unwrap_or_else(|| "-".to_owned())
+}
+
+/// Replaces the values of any [REDACTED_QUERY_PARAMS] in a query string with the hex BLAKE3 hash
+/// of the decoded value.
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) if REDACTED_QUERY_PARAMS.contains(&key) => {
+                let value = percent_decode_str(value).decode_utf8_lossy();
+                format!("{key}={}", blake3::hash(value.as_bytes()).to_hex())
+            }
+            _ => pair.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Turns away requests whose host isn't one of the configured allowed hosts with a
+/// `421 Misdirected Request`, so nobody can point their own domain at this instance and have it
+/// generate embeds under their name. A host can be allowed with or without its port.
+pub async fn check_host(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let Some(allowed_hosts) = &state.allowed_hosts else {
+        return next.run(request).await;
+    };
+
+    // Requests that don't say which host they're for at all can't be for someone else's domain.
+    let Some(host) = request_host(request.headers(), request.uri()) else {
+        return next.run(request).await;
+    };
+    let host = host.to_ascii_lowercase();
+    let hostname = host
+        .rsplit_once(':')
+        .map_or(host.as_str(), |(name, _)| name);
+
+    let allowed = allowed_hosts
+        .iter()
+        .any(|allowed| *allowed == host || allowed == hostname);
+
+    match allowed {
+        true => next.run(request).await,
+        false => (
+            StatusCode::MISDIRECTED_REQUEST,
+            "This host is not served by this vxsky instance",
+        )
+            .into_response(),
+    }
+}
+
+/// Rewrites the plain text error responses produced by handlers and extractors into either a JSON
+/// body or a minimal HTML error page, depending on what the client asked for in its `Accept`
+/// header. Server errors are also reported to Sentry along the way.
+pub async fn handle_error(request: Request, next: Next) -> Response {
+    let wants_json = prefers_json(request.headers());
+    let response = next.run(request).await;
+
+    let status = response.status();
+    if !(status.is_client_error() || status.is_server_error()) || !is_plain_text(&response) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let message = match body::to_bytes(body, MAX_ERROR_BODY_BYTES).await {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(_) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+
+    // Fall back to the standard reason phrase for errors that didn't come with a message.
+    let message = match message.is_empty() {
+        true => status
+            .canonical_reason()
+            .unwrap_or("Unknown error")
+            .to_owned(),
+        false => message,
+    };
+
+    // Server errors get reported to Sentry, this does nothing if Sentry hasn't been configured.
+    if status.is_server_error() {
+        sentry::capture_message(&message, sentry::Level::Error);
+    }
+
+    if wants_json {
+        let body = ErrorBody {
+            error: message,
+            code: status.as_u16(),
+        };
+        (status, Json(body)).into_response()
+    } else {
+        let page = ErrorPage {
+            code: status.as_u16(),
+            message,
+        };
+        (status, page).into_response()
+    }
+}
+
+/// Turns a panic in a handler into a `500 Internal Server Error` instead of taking the connection
+/// down with it, used with `CatchPanicLayer`. The plain text response is picked up by
+/// [handle_error] like any other error.
+pub fn handle_panic(err: Box<dyn Any + Send + 'static>) -> Response {
+    let details = match err.downcast_ref::<String>() {
+        Some(message) => message.as_str(),
+        None => err
+            .downcast_ref::<&str>()
+            .copied()
+            .unwrap_or("unknown panic"),
+    };
+    error!("A handler panicked: {details}");
+
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "An unexpected error occurred",
+    )
+        .into_response()
+}
+
+/// Gzip compresses image responses for clients that accept it, streaming the compressed bytes out
+/// with chunked transfer encoding as they're produced rather than compressing the whole image
+/// before sending anything.
+pub async fn compress_images(request: Request, next: Next) -> Response {
+    let accepts_gzip = request
+        .headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|encodings| encodings.split(',').any(accepts_gzip_encoding))
+        .unwrap_or(false);
+
+    let mut response = next.run(request).await;
+    if !is_image(&response) {
+        return response;
+    }
+
+    // Both the compressed and uncompressed versions need this, otherwise a shared cache could hand
+    // the gzip one to a client that never asked for it.
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+    if !accepts_gzip || response.headers().contains_key(header::CONTENT_ENCODING) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let (writer, reader) = tokio::io::duplex(COMPRESSION_BUFFER_BYTES);
+
+    tokio::spawn(async move {
+        let mut encoder = GzipEncoder::new(writer);
+        let mut chunks = body.into_data_stream();
+        while let Some(chunk) = chunks.next().await {
+            let written = match chunk {
+                Ok(chunk) => encoder.write_all(&chunk).await,
+                Err(err) => {
+                    warn!("Failed to read image body for compression: {err}");
+                    return;
+                }
+            };
+            // The client has gone away, so there's nobody to send the rest to.
+            if written.is_err() {
+                return;
+            }
+        }
+        let _ = encoder.shutdown().await;
+    });
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+
+    Response::from_parts(parts, Body::from_stream(ReaderStream::new(reader)))
+}
+
+/// Checks whether one entry of an `Accept-Encoding` header accepts gzip, which it doesn't if its
+/// quality is zero (e.g. `gzip;q=0`).
+fn accepts_gzip_encoding(encoding: &str) -> bool {
+    let mut params = encoding.split(';');
+    if !params
+        .next()
+        .is_some_and(|name| name.trim().eq_ignore_ascii_case("gzip"))
+    {
+        return false;
+    }
+
+    let quality = params.find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("q")
+            .then(|| value.trim().parse::<f32>().unwrap_or(0.0))
+    });
+    !matches!(quality, Some(quality) if quality <= 0.0)
+}
+
+/// Adds a `Content-Security-Policy` header to HTML responses, so even if something slips through in
+/// a post's text the page can only load scripts, frames and images from vxsky itself and the
+/// bluesky CDN.
+pub async fn content_security_policy(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let host = request_host(request.headers(), request.uri());
+
+    let mut response = next.run(request).await;
+    // Pages that need a different policy, like AMP pages loading the AMP runtime, set their own.
+    if !is_html(&response)
+        || response
+            .headers()
+            .contains_key(header::CONTENT_SECURITY_POLICY)
+    {
+        return response;
+    }
+
+    let base_url = &state.for_host(host.as_deref()).base_url;
+    let policy = format!(
+        "default-src 'none'; \
+         script-src 'self' {base_url}; \
+         frame-src 'self' {base_url}; \
+         img-src 'self' {base_url} https://cdn.bsky.app https://av-cdn.bsky.app; \
+         style-src 'unsafe-inline'; \
+         base-uri 'none'; \
+         form-action 'self' {base_url}"
+    );
+    let policy = if response.extensions().get::<Framable>().is_some() {
+        format!("{policy}; frame-ancestors *")
+    } else {
+        policy
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&policy) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_SECURITY_POLICY, value);
+    }
+
+    response
+}
+
+/// Marks a response as a page meant to be put in an `<iframe>` on other sites, so it is let through
+/// with `frame-ancestors *` instead of getting `X-Frame-Options: SAMEORIGIN`.
+#[derive(Clone, Copy)]
+pub struct Framable;
+
+/// Builds a layer that only lets pages be framed by this site, except for the ones marked as
+/// [Framable].
+pub fn frame_options() -> SetResponseHeaderLayer<impl Fn(&Response) -> Option<HeaderValue> + Clone>
+{
+    let value = HeaderValue::from_static("SAMEORIGIN");
+    SetResponseHeaderLayer::if_not_present(header::X_FRAME_OPTIONS, move |response: &Response| {
+        let framable = response.extensions().get::<Framable>().is_some();
+        (!is_image(response) && !framable).then(|| value.clone())
+    })
+}
+
+/// Builds a layer that sets a security header on every response that isn't an image, since images
+/// get embedded all over the place and the headers only matter for pages and scripts.
+pub fn security_header(
+    name: HeaderName,
+    value: &'static str,
+) -> SetResponseHeaderLayer<impl Fn(&Response) -> Option<HeaderValue> + Clone> {
+    let value = HeaderValue::from_static(value);
+    SetResponseHeaderLayer::if_not_present(name, move |response: &Response| {
+        (!is_image(response)).then(|| value.clone())
+    })
+}
+
+/// Checks whether the client would prefer a JSON response over HTML, going by which of the two
+/// shows up first in its `Accept` header.
+pub fn prefers_json(headers: &HeaderMap) -> bool {
+    let Some(accept) = headers
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    match (accept.find("application/json"), accept.find("text/html")) {
+        (Some(json), Some(html)) => json < html,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+/// Checks whether a response is plain text, or has no content type at all.
+fn is_plain_text(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|content_type| content_type.starts_with("text/plain"))
+        .unwrap_or(true)
+}
+
+/// Checks whether a response is an HTML page.
+fn is_html(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|content_type| content_type.starts_with("text/html"))
+        .unwrap_or(false)
+}
+
+/// Checks whether a response is an image.
+fn is_image(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|content_type| content_type.starts_with("image/"))
+        .unwrap_or(false)
+}
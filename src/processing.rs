@@ -1,17 +1,38 @@
 //! Image processing functions for generating combined thumbnails.
 
-use std::io::Cursor;
+use std::{
+    fmt,
+    io::Cursor,
+    time::Duration,
+};
 
+use axum::{
+    body::Bytes,
+    http::header,
+    response::{
+        IntoResponse,
+        Response,
+    },
+};
 use image::{
+    codecs::gif::GifDecoder,
     imageops,
     imageops::FilterType,
+    AnimationDecoder,
     DynamicImage,
     GenericImageView,
     ImageBuffer,
     ImageError,
+    ImageFormat,
     ImageOutputFormat,
     Rgb,
     RgbImage,
+    Rgba,
+    RgbaImage,
+};
+use imageproc::{
+    drawing::draw_filled_rect_mut,
+    rect::Rect,
 };
 use log::debug;
 use rayon::prelude::*;
@@ -22,62 +43,315 @@ use thiserror::Error;
 pub enum ProcessingError {
     #[error("Image array is empty")]
     EmptyImageArray,
-    #[error("Image array has too many images, maximum is 4")]
+    #[error("Image array has too many images, maximum is 5")]
     TooManyImages,
     #[error("Could not find image with most pixels, array is likely empty")]
     CouldNotFindMostPixels,
+    #[error("Image decoding error: {0}")]
+    ImageDecodingError(#[source] ImageError),
+    #[error("Animated image has no frames")]
+    EmptyAnimation,
+    #[error("Image has invalid dimensions {0}x{1}, both need to be above zero")]
+    InvalidImageDimensions(u32, u32),
+    #[error("Combined image canvas has invalid dimensions {0}x{1}, both need to be above zero")]
+    InvalidCanvasDimensions(u32, u32),
+    #[error("Combined image canvas of {0}x{1} is too large, maximum is {2} pixels")]
+    CanvasTooLarge(u32, u32, u64),
     #[error("Image encoding error: {0}")]
-    ImageError(#[from] ImageError),
+    ImageEncodingError(#[source] ImageError),
     #[error("Failed to blur image: {0}")]
     BlurSliceSizeError(#[from] blurslice::SliceSizeError),
     #[error("Failed to blur image, final image buffer could not be allocated")]
     BlurBufferError,
 }
 
+/// Options that control how combined thumbnails are generated.
+#[derive(Clone, Debug)]
+pub struct ProcessingConfig {
+    /// The radius of the gaussian blur applied to the background of the combined thumbnail.
+    pub blur_radius: f32,
+    /// Whether to center-crop the combined thumbnail to the 1200x630 size recommended for
+    /// OpenGraph images by Twitter and Facebook.
+    pub crop_to_og_ratio: bool,
+    /// The color behind the blurred background, which shows through anywhere the images don't
+    /// quite cover the whole thumbnail.
+    pub bg_color: Rgba<u8>,
+    /// The format combined thumbnails are encoded with, either PNG or JPEG.
+    pub output_format: ImageFormat,
+    /// The quality used when encoding the thumbnail with a lossy format, from 0 to 100.
+    pub quality: u8,
+    /// The largest number of pixels the combined canvas can have, so a few big images can't make
+    /// the server allocate and blur an enormous image.
+    pub max_total_pixels: u64,
+    /// The width of the lines drawn between images in the combined thumbnail, no lines are drawn
+    /// if this is zero.
+    pub border_width: u32,
+    /// The color of the lines drawn between images.
+    pub border_color: Rgba<u8>,
+    /// The largest image that will be downloaded for a thumbnail, in bytes.
+    pub max_image_bytes: u64,
+    /// How many images can be downloaded at once for a single thumbnail.
+    pub max_concurrent_downloads: usize,
+    /// How long an image can take to download before it's logged as slow.
+    pub slow_download_threshold: Duration,
+}
+
+impl Default for ProcessingConfig {
+    fn default() -> Self {
+        ProcessingConfig {
+            blur_radius: 50.0,
+            crop_to_og_ratio: false,
+            bg_color: Rgba([0, 0, 0, 255]),
+            output_format: ImageFormat::Png,
+            quality: 85,
+            max_total_pixels: 4_000_000,
+            border_width: 0,
+            border_color: Rgba([255, 255, 255, 255]),
+            max_image_bytes: 10 * 1024 * 1024,
+            max_concurrent_downloads: 4,
+            slow_download_threshold: Duration::from_millis(2000),
+        }
+    }
+}
+
+/// The weakest blur used for the background of an obscured thumbnail.
+const MIN_OBSCURED_BLUR_RADIUS: f32 = 50.0;
+
+/// The width of the recommended OpenGraph image size.
+const OG_IMAGE_WIDTH: u32 = 1200;
+/// The height of the recommended OpenGraph image size.
+const OG_IMAGE_HEIGHT: u32 = 630;
+
 /// A basic wrapper struct to hold a combined thumbnail's bytes for passing back from an axum
-/// handler.
+/// handler, cheap to clone so it can be handed out from the cache.
+#[derive(Clone)]
 pub struct CombinedThumbnail {
-    inner: Vec<u8>,
+    inner: Bytes,
+    format: ImageFormat,
+    dimensions: (u32, u32),
 }
 
 impl CombinedThumbnail {
-    pub fn new(image: DynamicImage, format: ImageOutputFormat) -> Result<Self, ImageError> {
+    /// Encodes an image into a thumbnail, `quality` is applied to lossy formats and ignored for
+    /// lossless ones.
+    pub fn new(
+        image: DynamicImage,
+        format: ImageFormat,
+        quality: u8,
+    ) -> Result<Self, ProcessingError> {
+        let (image, output_format) = match format {
+            // JPEG has no alpha channel, so it has to be dropped before encoding.
+            ImageFormat::Jpeg => (
+                DynamicImage::ImageRgb8(image.to_rgb8()),
+                ImageOutputFormat::Jpeg(quality),
+            ),
+            format => (image, ImageOutputFormat::from(format)),
+        };
+
+        let dimensions = image.dimensions();
         let mut buffer = Cursor::new(Vec::new());
-        image.write_to(&mut buffer, format)?;
+        image
+            .write_to(&mut buffer, output_format)
+            .map_err(ProcessingError::ImageEncodingError)?;
 
         Ok(CombinedThumbnail {
-            inner: buffer.into_inner(),
+            inner: buffer.into_inner().into(),
+            format,
+            dimensions,
         })
     }
 
-    pub fn to_bytes(&self) -> &[u8] {
-        &self.inner
+    /// The format the thumbnail was encoded with.
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+
+    /// The width and height of the thumbnail in pixels.
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.dimensions
+    }
+}
+
+impl IntoResponse for CombinedThumbnail {
+    fn into_response(self) -> Response {
+        let content_type = self.format().to_mime_type();
+        ([(header::CONTENT_TYPE, content_type)], self.inner).into_response()
+    }
+}
+
+impl fmt::Debug for CombinedThumbnail {
+    /// Only shows the size and format of the encoded image, printing out the raw bytes would just
+    /// flood the logs.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CombinedThumbnail")
+            .field("len", &self.inner.len())
+            .field("format", &self.format)
+            .field("dimensions", &self.dimensions)
+            .finish()
+    }
+}
+
+/// Decode a downloaded image from its raw bytes, guessing the format from its contents.
+pub fn decode_image(bytes: &[u8]) -> Result<DynamicImage, ProcessingError> {
+    image::load_from_memory(bytes).map_err(ProcessingError::ImageDecodingError)
+}
+
+/// Checks that a decoded image actually has pixels in it, a corrupt download can decode to an image
+/// with zero width or height which would throw off the thumbnail layout.
+pub fn validate_dimensions(image: &DynamicImage) -> Result<(), ProcessingError> {
+    let (width, height) = image.dimensions();
+    match width > 0 && height > 0 {
+        true => Ok(()),
+        false => Err(ProcessingError::InvalidImageDimensions(width, height)),
     }
 }
 
+/// Decode the first frame of an animated GIF, which is used in place of the whole animation when
+/// compositing the combined thumbnail.
+pub fn decode_gif_first_frame(bytes: &[u8]) -> Result<DynamicImage, ProcessingError> {
+    let decoder =
+        GifDecoder::new(Cursor::new(bytes)).map_err(ProcessingError::ImageDecodingError)?;
+    let frame = decoder
+        .into_frames()
+        .next()
+        .ok_or(ProcessingError::EmptyAnimation)?
+        .map_err(ProcessingError::ImageDecodingError)?;
+
+    Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+}
+
 /// Generate a combined thumbnail from a list of images, adding a nice blur effect as a background.
+/// When `obscured` is set the images are left out and only the blurred background is kept, for
+/// posts with content warnings.
 pub fn generate_combined_thumbnail(
     images: Vec<DynamicImage>,
+    config: &ProcessingConfig,
+    obscured: bool,
 ) -> Result<CombinedThumbnail, ProcessingError> {
     let total_size = get_total_img_size(&images)?;
-    let combined = combine_images(&images, total_size.0, total_size.1, true)?;
+    let total_pixels = total_size.0 as u64 * total_size.1 as u64;
+    if total_pixels > config.max_total_pixels {
+        return Err(ProcessingError::CanvasTooLarge(
+            total_size.0,
+            total_size.1,
+            config.max_total_pixels,
+        ));
+    }
+
     let background = combine_images(&images, total_size.0, total_size.1, false)?;
-    let mut blurred_bg = blur_background(&mut background.to_rgb8())?;
 
-    imageops::overlay(&mut blurred_bg, &combined, 0, 0);
+    // Fill in the background color first so any gaps between images don't just end up black.
+    let mut canvas = DynamicImage::ImageRgba8(RgbaImage::from_pixel(
+        total_size.0,
+        total_size.1,
+        config.bg_color,
+    ));
+    imageops::overlay(&mut canvas, &background, 0, 0);
+    // Obscured thumbnails are only the background, so it has to stay blurred beyond recognition
+    // however weak the configured blur is.
+    let blur_radius = match obscured {
+        true => config.blur_radius.max(MIN_OBSCURED_BLUR_RADIUS),
+        false => config.blur_radius,
+    };
+    let mut blurred_bg = blur_background(&mut canvas.to_rgb8(), blur_radius)?;
+
+    // Lighten the background a little so images with dark borders don't blur into dark edges that
+    // blend in with the images on top.
+    imageops::colorops::brighten_in_place(&mut blurred_bg, 20);
+
+    if !obscured {
+        let combined = combine_images(&images, total_size.0, total_size.1, true)?;
+        imageops::overlay(&mut blurred_bg, &combined, 0, 0);
+
+        if config.border_width > 0 && images.len() > 1 {
+            let layout = image_grid_layout(&images)?;
+            draw_borders(&mut blurred_bg, &layout, images.len(), config);
+        }
+    }
+
+    if config.crop_to_og_ratio {
+        blurred_bg = crop_to_og_ratio(&blurred_bg);
+    }
 
-    let thumbnail = CombinedThumbnail::new(blurred_bg, ImageOutputFormat::Png)?;
+    let thumbnail = CombinedThumbnail::new(blurred_bg, config.output_format, config.quality)?;
     Ok(thumbnail)
 }
 
+/// Draws lines along the edges between the cells of a grid of images, matching how
+/// [combine_images] stretches rows that aren't full across the whole width.
+fn draw_borders(
+    image: &mut DynamicImage,
+    layout: &GridLayout,
+    image_count: usize,
+    config: &ProcessingConfig,
+) {
+    let (width, height) = image.dimensions();
+    let half = (config.border_width / 2) as i32;
+
+    for row in 1..layout.rows {
+        let y = (row * layout.cell_height) as i32 - half;
+        let rect = Rect::at(0, y).of_size(width, config.border_width);
+        draw_filled_rect_mut(image, rect, config.border_color);
+    }
+
+    let indices = (0..image_count).collect::<Vec<_>>();
+    for (row, row_images) in indices.chunks(layout.cols as usize).enumerate() {
+        let cell_width = width / row_images.len() as u32;
+        let top = row as u32 * layout.cell_height;
+        let row_height = layout.cell_height.min(height.saturating_sub(top));
+
+        for col in 1..row_images.len() as u32 {
+            let x = (col * cell_width) as i32 - half;
+            let rect = Rect::at(x, top as i32).of_size(config.border_width, row_height);
+            draw_filled_rect_mut(image, rect, config.border_color);
+        }
+    }
+}
+
+/// Center-crops an image to the 1.91:1 OpenGraph aspect ratio, then scales it to exactly 1200x630.
+fn crop_to_og_ratio(image: &DynamicImage) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return image.clone();
+    }
+    let target_ratio = OG_IMAGE_WIDTH as f64 / OG_IMAGE_HEIGHT as f64;
+
+    // Take the largest region with the target aspect ratio that fits inside the image.
+    // Very thin images round down to nothing on the short side, so it's kept to at least a pixel.
+    let (crop_width, crop_height) = if width as f64 / height as f64 > target_ratio {
+        (((height as f64 * target_ratio) as u32).max(1), height)
+    } else {
+        (width, ((width as f64 / target_ratio) as u32).max(1))
+    };
+
+    let x = (width - crop_width) / 2;
+    let y = (height - crop_height) / 2;
+    debug!("Cropping thumbnail to {crop_width}x{crop_height} at x: {x}, y: {y}");
+
+    let cropped = imageops::crop_imm(image, x, y, crop_width, crop_height).to_image();
+    DynamicImage::ImageRgba8(cropped).resize_exact(
+        OG_IMAGE_WIDTH,
+        OG_IMAGE_HEIGHT,
+        FilterType::Lanczos3,
+    )
+}
+
 /// Takes a [`DynamicImage`] and applies a fast gaussian blur effect to it.
-fn blur_background(background: &mut RgbImage) -> Result<DynamicImage, ProcessingError> {
+fn blur_background(
+    background: &mut RgbImage,
+    radius: f32,
+) -> Result<DynamicImage, ProcessingError> {
     let start = std::time::Instant::now();
     debug!("Blurring background: {:?}", background.dimensions());
 
+    // A blur radius of zero means no blur at all, which the blur itself doesn't handle.
+    if radius <= 0.0 {
+        return Ok(DynamicImage::ImageRgb8(background.to_owned()));
+    }
+
     let (width, height) = background.dimensions();
     let samples = background.as_flat_samples_mut();
-    blurslice::gaussian_blur_bytes::<3>(samples.samples, width as usize, height as usize, 50.0)
+    blurslice::gaussian_blur_bytes::<3>(samples.samples, width as usize, height as usize, radius)
         .map_err(ProcessingError::BlurSliceSizeError)?;
 
     let duration = start.elapsed();
@@ -101,6 +375,55 @@ fn layout_horizontal(new_image: &mut DynamicImage, images: &[DynamicImage], y_of
     }
 }
 
+/// How the images in a combined thumbnail are arranged, as rows of equally sized cells.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct GridLayout {
+    rows: u32,
+    cols: u32,
+    cell_width: u32,
+    cell_height: u32,
+}
+
+impl GridLayout {
+    /// The total width of the grid.
+    fn width(&self) -> u32 {
+        self.cols * self.cell_width
+    }
+
+    /// The total height of the grid.
+    fn height(&self) -> u32 {
+        self.rows * self.cell_height
+    }
+}
+
+/// Picks the grid used to lay out a set of images, where each cell is the size of the biggest
+/// image. Images fill the grid row by row, and a row that isn't full is stretched across the whole
+/// width.
+fn image_grid_layout(images: &[DynamicImage]) -> Result<GridLayout, ProcessingError> {
+    let (rows, cols) = match images.len() {
+        0 => return Err(ProcessingError::EmptyImageArray),
+        1 => (1, 1),
+        // Putting two tall images side by side leaves a wide strip with lots of padding around
+        // each one, so a pair of portrait images is stacked on top of each other instead. Any more
+        // than that and the stack gets far too tall, so they use the grid.
+        2 if images.iter().all(|image| image.height() > image.width()) => (2, 1),
+        2 => (1, 2),
+        3 | 4 => (2, 2),
+        // Bluesky only allows four images right now, five gets a 2x2 grid with the fifth image
+        // across the bottom in case that changes.
+        5 => (3, 2),
+        _ => return Err(ProcessingError::TooManyImages),
+    };
+
+    let (cell_width, cell_height) = find_img_with_most_pixels(images)?.dimensions();
+    Ok(GridLayout {
+        rows,
+        cols,
+        cell_width,
+        cell_height,
+    })
+}
+
 /// Takes a slice of images and combines them into a single image, appropriately laid out based on
 /// the number of images and their sizes.
 fn combine_images(
@@ -109,58 +432,34 @@ fn combine_images(
     total_height: u32,
     pad: bool,
 ) -> Result<DynamicImage, ProcessingError> {
+    if total_width == 0 || total_height == 0 {
+        return Err(ProcessingError::InvalidCanvasDimensions(
+            total_width,
+            total_height,
+        ));
+    }
+
     // If there is only one image, return it
     if images.len() == 1 {
         return Ok(images[0].to_owned());
     }
 
     let mut new_image = DynamicImage::new_rgba8(total_width, total_height);
-    let top_img = find_img_with_most_pixels(images)?;
-
-    let mut scaled_images =
-        scale_all_images_to_same_size(images, top_img.width(), top_img.height(), pad);
-
-    match scaled_images.len() {
-        0 => return Err(ProcessingError::EmptyImageArray),
-        1 => return Ok(images[0].to_owned()),
-        2 => {
-            // If there are two images, combine them horizontally
-            layout_horizontal(&mut new_image, &scaled_images, 0);
-        }
-        3 => {
-            // If there are three images, combine the first two horizontally, then combine the last
-            // one vertically
-            layout_horizontal(&mut new_image, &scaled_images[..2], 0);
-
-            // Take the last image, treat it like an image array and scale it to the total width,
-            // but with the same height as all individual images. We use the unscaled image to
-            // prevent resizing the image twice down twice making it too small.
-            let processed_last_img = scale_all_images_to_same_size(
-                &[images[2].to_owned()],
-                total_width,
-                top_img.height(),
-                pad,
-            );
-            scaled_images[2] = processed_last_img.first().unwrap().to_owned();
-
-            // Overlay the third image below the first two with a vertical offset
-            layout_horizontal(
-                &mut new_image,
-                &scaled_images[2..],
-                scaled_images[0].height(),
-            );
-        }
-        4 => {
-            // If there are four images, combine the images in a 2x2 grid with the first two on top
-            // and the last two on the bottom
-            layout_horizontal(&mut new_image, &scaled_images[..2], 0);
-            layout_horizontal(
-                &mut new_image,
-                &scaled_images[2..],
-                scaled_images[0].height(),
-            );
-        }
-        _ => return Err(ProcessingError::TooManyImages),
+    let layout = image_grid_layout(images)?;
+
+    for (row, row_images) in images.chunks(layout.cols as usize).enumerate() {
+        // Rows that aren't full have their images stretched to fill the width, like the last one
+        // when there are three images. The unscaled images are used so nothing is resized twice
+        // and ends up too small.
+        let cell_width = total_width / row_images.len() as u32;
+        let scaled_images =
+            scale_all_images_to_same_size(row_images, cell_width, layout.cell_height, pad);
+
+        layout_horizontal(
+            &mut new_image,
+            &scaled_images,
+            row as u32 * layout.cell_height,
+        );
     }
 
     Ok(new_image)
@@ -176,14 +475,8 @@ fn find_img_with_most_pixels(images: &[DynamicImage]) -> Result<&DynamicImage, P
 
 /// Get the total size of the combined image, based on the number of images.
 fn get_total_img_size(images: &[DynamicImage]) -> Result<(u32, u32), ProcessingError> {
-    let max_image = find_img_with_most_pixels(images)?;
-    let (width, height) = max_image.dimensions();
-    let size = match images.len() {
-        1 => (width, height),
-        2 => (width * 2, height),
-        _ => (width * 2, height * 2),
-    };
-    Ok(size)
+    let layout = image_grid_layout(images)?;
+    Ok((layout.width(), layout.height()))
 }
 
 /// Scale an image to a target width and height, with an optional padding to fill the target size in
@@ -0,0 +1,410 @@
+//! Resolving accounts and posts straight from the PDS hosting them, for accounts on PDS instances
+//! other than `bsky.social` that the bluesky API can't resolve or hasn't indexed.
+
+use std::{
+    net::{
+        IpAddr,
+        SocketAddr,
+    },
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{
+    anyhow,
+    bail,
+    Context,
+};
+use atrium_api::{
+    app::bsky::{
+        actor::{
+            defs::ProfileViewBasic,
+            profile::RecordLabelsEnum as ProfileLabelsEnum,
+        },
+        feed::{
+            defs::PostView,
+            post::RecordLabelsEnum,
+        },
+    },
+    com::atproto::{
+        label::defs::Label,
+        repo::get_record,
+    },
+    records::Record,
+};
+use hyper::client::connect::dns::Name;
+use log::warn;
+use percent_encoding::{
+    percent_decode_str,
+    utf8_percent_encode,
+    NON_ALPHANUMERIC,
+};
+use reqwest::{
+    dns::{
+        Addrs,
+        Resolve,
+        Resolving,
+    },
+    redirect,
+    Client,
+    Url,
+};
+use serde::Deserialize;
+
+use crate::identity::{
+    Did,
+    Handle,
+};
+
+/// The directory that `did:plc` documents are published to.
+const PLC_DIRECTORY_URL: &str = "https://plc.directory";
+
+/// The fragment identifying the PDS service in a DID document.
+const PDS_SERVICE_ID: &str = "#atproto_pds";
+
+/// The largest response read from a PDS or handle domain, DID documents and records are tiny so
+/// anything bigger is someone trying to use up our memory.
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+/// How many redirects a request to a PDS or handle domain can follow.
+const MAX_REDIRECTS: usize = 3;
+
+/// Builds the HTTP client used for resolving, which only ever connects to public addresses since
+/// the hosts it talks to come from handles and DID documents anyone can publish.
+pub fn client(timeout: Duration) -> reqwest::Result<Client> {
+    let policy = redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if !is_public_url(attempt.url()) {
+            attempt.error("redirected to a non-public address")
+        } else {
+            attempt.follow()
+        }
+    });
+
+    Client::builder()
+        .timeout(timeout)
+        .redirect(policy)
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+}
+
+/// A DNS resolver that leaves out private, loopback and other non-public addresses, so handles and
+/// DID documents can't point requests at the network this is hosted on.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(format!("{} has no public addresses", name.as_str()).into());
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether a URL is HTTPS and not an IP literal for a non-public address, which would skip DNS
+/// resolution and so the [PublicResolver].
+fn is_public_url(url: &Url) -> bool {
+    if url.scheme() != "https" {
+        return false;
+    }
+
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    // IPv6 literals keep their brackets in the host.
+    match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => is_public(ip),
+        Err(_) => true,
+    }
+}
+
+/// Whether an address is reachable on the public internet.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7.
+                    || (first & 0xfe00) == 0xfc00
+                    // Link local, fe80::/10.
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Fetches a URL with the resolving client, refusing ones that point at non-public addresses.
+async fn fetch(url: &str, client: &Client) -> anyhow::Result<Vec<u8>> {
+    let parsed = Url::parse(url)?;
+    if !is_public_url(&parsed) {
+        bail!("{url} isn't a public HTTPS address");
+    }
+
+    let mut response = client.get(parsed).send().await?.error_for_status()?;
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_RESPONSE_BYTES as u64)
+    {
+        bail!("{url} is larger than {MAX_RESPONSE_BYTES} bytes");
+    }
+
+    // The reported length can't be trusted, so the body is read in chunks and cut off past the
+    // limit either way.
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_RESPONSE_BYTES {
+            bail!("{url} is larger than {MAX_RESPONSE_BYTES} bytes");
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// The parts of a DID document needed to find where an account is hosted.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidDocument {
+    /// The account's handles as `at://` URIs, the first one being the current handle.
+    #[serde(default)]
+    also_known_as: Vec<String>,
+    #[serde(default)]
+    service: Vec<DidService>,
+}
+
+/// A service listed in a [DidDocument].
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidService {
+    id: String,
+    service_endpoint: String,
+}
+
+/// Where an account is hosted, resolved from its DID document.
+#[derive(Debug)]
+pub struct PdsIdentity {
+    /// The URL of the PDS hosting the account (e.g. "https://custom-pds.com").
+    pub endpoint: String,
+    /// The account's handle, if the DID document claims one and the handle points back at the DID.
+    pub handle: Option<String>,
+}
+
+/// Resolves a handle through the `/.well-known/atproto-did` file served from the handle's domain,
+/// which the PDS hosting the account serves for handles under its own domain.
+pub async fn resolve_handle(handle: &Handle, client: &Client) -> anyhow::Result<Did> {
+    let url = format!("https://{handle}/.well-known/atproto-did");
+    let body = fetch(&url, client).await?;
+    let body = std::str::from_utf8(&body)?;
+
+    Ok(Did::try_from(body.trim().to_owned())?)
+}
+
+/// Fetches the DID document for a DID and finds the PDS hosting the account. `did:plc` documents
+/// come from the PLC directory and `did:web` documents from the domain in the DID.
+pub async fn resolve_pds(did: &Did, client: &Client) -> anyhow::Result<PdsIdentity> {
+    let account = did;
+    let did = did.to_string();
+    let url = match did.strip_prefix("did:web:") {
+        // Ports are percent-encoded in did:web, and atproto doesn't allow path based ones.
+        Some(host) if !host.contains(':') => {
+            let host = percent_decode_str(host).decode_utf8()?;
+            format!("https://{host}/.well-known/did.json")
+        }
+        Some(_) => bail!("{did} is a path based did:web, which atproto doesn't support"),
+        None if did.starts_with("did:plc:") => format!("{PLC_DIRECTORY_URL}/{did}"),
+        None => bail!("{did} uses an unsupported DID method"),
+    };
+
+    let bytes = fetch(&url, client).await?;
+    let document: DidDocument = serde_json::from_slice(&bytes)
+        .with_context(|| format!("parsing the DID document for {did}"))?;
+
+    let endpoint = document
+        .service
+        .iter()
+        .find(|service| service.id == PDS_SERVICE_ID || service.id.ends_with(PDS_SERVICE_ID))
+        .map(|service| service.service_endpoint.trim_end_matches('/').to_owned())
+        .ok_or_else(|| anyhow!("The DID document for {did} doesn't list a PDS"))?;
+
+    let claimed_handle = document
+        .also_known_as
+        .iter()
+        .find_map(|aka| aka.strip_prefix("at://"));
+    let handle = match claimed_handle {
+        Some(handle) => verify_handle(handle, account, client).await,
+        None => None,
+    };
+
+    Ok(PdsIdentity { endpoint, handle })
+}
+
+/// Checks that a handle claimed by a DID document resolves back to the same DID, since anyone can
+/// claim any handle in their own document. Returns the handle only if it does.
+async fn verify_handle(handle: &str, did: &Did, client: &Client) -> Option<String> {
+    let handle = Handle::try_from(handle.to_owned()).ok()?;
+    match resolve_handle(&handle, client).await {
+        Ok(resolved) if resolved == *did => Some(handle.to_string()),
+        Ok(resolved) => {
+            warn!("{did} claims the handle {handle}, which belongs to {resolved}");
+            None
+        }
+        Err(err) => {
+            warn!("Couldn't verify the handle {handle} claimed by {did}: {err:?}");
+            None
+        }
+    }
+}
+
+/// Fetches a post's record straight from the PDS hosting it and builds a bare [PostView] around it.
+/// The view only has what the records and DID document say, there's no display name, avatar or
+/// engagement counts since those come from the bluesky API. The only labels are the ones the author
+/// put on the post and their profile themselves, which is where gating and content warnings come
+/// from for an account the bluesky API doesn't know about.
+pub async fn get_post(uri: &str, client: &Client) -> anyhow::Result<PostView> {
+    let (did, rkey) = uri
+        .strip_prefix("at://")
+        .and_then(|path| path.split_once("/app.bsky.feed.post/"))
+        .ok_or_else(|| anyhow!("{uri} isn't the ATUri of a post"))?;
+    let did = Did::try_from(did.to_owned())?;
+    let pds = resolve_pds(&did, client).await?;
+
+    let url = format!(
+        "{}/xrpc/com.atproto.repo.getRecord?repo={}&collection=app.bsky.feed.post&rkey={}",
+        pds.endpoint,
+        utf8_percent_encode(&did.to_string(), NON_ALPHANUMERIC),
+        utf8_percent_encode(rkey, NON_ALPHANUMERIC),
+    );
+    let bytes = fetch(&url, client).await?;
+    let output: get_record::Output = serde_json::from_slice(&bytes)
+        .with_context(|| format!("parsing the record for {uri} from {}", pds.endpoint))?;
+
+    let (created_at, labels) = match &output.value {
+        Record::AppBskyFeedPost(record) => (
+            record.created_at.to_owned(),
+            self_labels(&output.uri, &did, &record.created_at, &record.labels),
+        ),
+        _ => bail!("The record for {uri} isn't a post"),
+    };
+    let profile_labels = get_profile_labels(&did, &pds, client).await?;
+
+    Ok(PostView {
+        author: ProfileViewBasic {
+            avatar: None,
+            did: did.to_string(),
+            display_name: None,
+            handle: pds.handle.unwrap_or_else(|| did.to_string()),
+            labels: Some(profile_labels),
+            viewer: None,
+        },
+        cid: output.cid.unwrap_or_default(),
+        // The images are picked up from the record instead.
+        embed: None,
+        indexed_at: created_at,
+        labels: Some(labels),
+        like_count: None,
+        record: output.value,
+        reply_count: None,
+        repost_count: None,
+        threadgate: None,
+        uri: output.uri,
+        viewer: None,
+    })
+}
+
+/// Fetches the self labels on an account's profile record from its PDS, an account without a
+/// profile record has no labels.
+async fn get_profile_labels(
+    did: &Did,
+    pds: &PdsIdentity,
+    client: &Client,
+) -> anyhow::Result<Vec<Label>> {
+    let url = format!(
+        "{}/xrpc/com.atproto.repo.getRecord?repo={}&collection=app.bsky.actor.profile&rkey=self",
+        pds.endpoint,
+        utf8_percent_encode(&did.to_string(), NON_ALPHANUMERIC),
+    );
+    // Missing profile records are an error response, but so is everything else, so this has to be
+    // strict to not let a flaky PDS drop the labels of an account that has them.
+    let bytes = match fetch(&url, client).await {
+        Ok(bytes) => bytes,
+        Err(err) if is_not_found(&err) => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let output: get_record::Output = serde_json::from_slice(&bytes)
+        .with_context(|| format!("parsing the profile of {did} from {}", pds.endpoint))?;
+
+    match &output.value {
+        Record::AppBskyActorProfile(profile) => Ok(profile
+            .labels
+            .iter()
+            .flat_map(|labels| match labels {
+                ProfileLabelsEnum::ComAtprotoLabelDefsSelfLabels(labels) => labels.values.iter(),
+            })
+            .map(|label| Label {
+                cid: None,
+                cts: String::new(),
+                neg: None,
+                src: did.to_string(),
+                uri: did.to_string(),
+                val: label.val.to_owned(),
+            })
+            .collect()),
+        _ => bail!("The profile record of {did} isn't a profile"),
+    }
+}
+
+/// Turns the self labels on a post record into the labels the bluesky API would give the post.
+fn self_labels(
+    uri: &str,
+    did: &Did,
+    created_at: &str,
+    labels: &Option<RecordLabelsEnum>,
+) -> Vec<Label> {
+    labels
+        .iter()
+        .flat_map(|labels| match labels {
+            RecordLabelsEnum::ComAtprotoLabelDefsSelfLabels(labels) => labels.values.iter(),
+        })
+        .map(|label| Label {
+            cid: None,
+            cts: created_at.to_owned(),
+            neg: None,
+            src: did.to_string(),
+            uri: uri.to_owned(),
+            val: label.val.to_owned(),
+        })
+        .collect()
+}
+
+/// Whether a request failed because the PDS doesn't have the record.
+fn is_not_found(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<reqwest::Error>()
+        .and_then(reqwest::Error::status)
+        .map(|status| status.as_u16() == 400 || status.as_u16() == 404)
+        .unwrap_or(false)
+}
@@ -0,0 +1,73 @@
+//! Retrying of flaky network operations with exponential backoff.
+
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{
+        BuildHasher,
+        Hasher,
+    },
+    time::Duration,
+};
+
+use log::warn;
+
+/// The delay before the first retry, doubled for each retry after that.
+const BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// Errors that can tell whether the operation that produced them is worth trying again.
+pub trait Retryable {
+    /// Returns `true` for transient failures like network errors, and `false` for semantic errors
+    /// like a post not existing where trying again would just give the same result.
+    fn is_retryable(&self) -> bool;
+}
+
+impl<E> Retryable for atrium_xrpc::error::Error<E> {
+    fn is_retryable(&self) -> bool {
+        match self {
+            atrium_xrpc::error::Error::HttpClient(_) => true,
+            atrium_xrpc::error::Error::XrpcResponse(response) => {
+                response.status.is_server_error() || response.status.as_u16() == 429
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Runs an async operation, retrying it up to `max_retries` times if it fails with a retryable
+/// error. Each retry waits twice as long as the last, with some random jitter added so lots of
+/// requests failing at once don't all retry at the same moment.
+pub async fn retry_with_backoff<F, Fut, T, E>(mut f: F, max_retries: u32) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Retryable + std::fmt::Display,
+{
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Err(err) if attempt < max_retries && err.is_retryable() => {
+                let delay = backoff_delay(attempt);
+                warn!(
+                    "Retrying in {delay:?} after attempt {} failed: {err}",
+                    attempt + 1
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Calculates how long to wait before the given retry attempt, adding up to 50% random jitter on
+/// top of the exponential delay.
+fn backoff_delay(attempt: u32) -> Duration {
+    let delay = BASE_DELAY * 2u32.saturating_pow(attempt);
+
+    // A freshly seeded hasher is an easy source of randomness without pulling in another crate.
+    let random = RandomState::new().build_hasher().finish();
+    let jitter = delay.mul_f64((random % 1000) as f64 / 2000.0);
+
+    delay + jitter
+}
@@ -0,0 +1,120 @@
+//! Background checking of the bluesky session, so operators find out when it expires instead of
+//! every embed quietly failing.
+
+use std::time::Duration;
+
+use atrium_api::com::atproto::server::get_session;
+use atrium_xrpc::error::{
+    Error,
+    ErrorResponseBody,
+    XrpcErrorKind,
+};
+use log::{
+    error,
+    info,
+    warn,
+};
+use reqwest::header;
+use serde::Serialize;
+
+use crate::state::AppState;
+
+/// How often the session is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The longest the checks are backed off for while the bluesky API is failing for other reasons.
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// The JSON payload posted to the webhook when the session expires.
+#[derive(Debug, Serialize)]
+struct WebhookEvent {
+    event: &'static str,
+    /// When the expiry was noticed, as an RFC 3339 timestamp.
+    timestamp: String,
+}
+
+/// Periodically checks that the bluesky session is still valid, posting to `webhook_url` if it has
+/// expired and couldn't be refreshed. The agent already tries to refresh an expired session on its
+/// own when a request fails, so a check failing means the refresh did too. Only one notification is
+/// sent until the session recovers, rather than one for every check. Other failures, like the API
+/// being down, back the checks off instead.
+pub async fn watch_session(state: AppState, webhook_url: Option<String>) {
+    let mut expired = false;
+    let mut delay = CHECK_INTERVAL;
+
+    loop {
+        let result = state.agent.api.com.atproto.server.get_session().await;
+        let previous_delay = delay;
+        delay = CHECK_INTERVAL;
+        match (result, expired) {
+            (Ok(_), true) => {
+                info!("The bluesky session is valid again");
+                expired = false;
+            }
+            (Ok(_), false) => {}
+            (Err(err), true) if is_auth_failure(&err) => {}
+            (Err(err), false) if is_auth_failure(&err) => {
+                error!("The bluesky session has expired and couldn't be refreshed: {err}");
+                expired = true;
+
+                if let Some(url) = &webhook_url {
+                    notify(&state, url).await;
+                }
+            }
+            (Err(err), _) => {
+                delay = (previous_delay * 2).min(MAX_BACKOFF);
+                warn!("Couldn't check the bluesky session, trying again in {delay:?}: {err}");
+            }
+        }
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Whether checking the session failed because its tokens are no good, rather than the API having
+/// trouble, which says nothing about the session.
+fn is_auth_failure(err: &Error<get_session::Error>) -> bool {
+    let Error::XrpcResponse(response) = err else {
+        return false;
+    };
+
+    let is_auth_status = matches!(response.status.as_u16(), 400 | 401);
+    let is_token_error = matches!(
+        &response.error,
+        Some(XrpcErrorKind::Undefined(ErrorResponseBody {
+            error: Some(error),
+            ..
+        })) if error == "ExpiredToken" || error == "InvalidToken"
+    );
+
+    is_auth_status && is_token_error
+}
+
+/// Posts a `session_expired` event to the webhook.
+async fn notify(state: &AppState, url: &str) {
+    let event = WebhookEvent {
+        event: "session_expired",
+        timestamp: chrono::Utc::now().to_rfc3339(),
+    };
+
+    let body = match serde_json::to_vec(&event) {
+        Ok(body) => body,
+        Err(err) => {
+            error!("Couldn't serialize the session webhook event: {err}");
+            return;
+        }
+    };
+
+    let result = state
+        .http_client
+        .post(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+
+    if let Err(err) = result {
+        error!("Couldn't send the session expiry webhook: {err}");
+    }
+}
@@ -0,0 +1,544 @@
+//! The shared application state passed to every request handler, and a builder to assemble it.
+
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use atrium_api::agent::{
+    store::MemorySessionStore,
+    AtpAgent,
+};
+use atrium_xrpc_client::reqwest::{
+    ReqwestClient,
+    ReqwestClientBuilder,
+};
+use axum::{
+    extract::FromRequestParts,
+    http::{
+        header,
+        request::Parts,
+        HeaderMap,
+        Uri,
+    },
+};
+use image::ImageFormat;
+use log::warn;
+use reqwest::Client;
+use thiserror::Error;
+
+use crate::{
+    access_log::AccessLog,
+    cache::{
+        self,
+        ImageCache,
+        TemplateCache,
+    },
+    env,
+    processing::ProcessingConfig,
+    resolver,
+    stats::Stats,
+    updates::{
+        self,
+        PostUpdates,
+    },
+    views::ViewCounter,
+};
+
+/// The [AtpAgent] type used to talk to the bluesky API.
+pub type Agent = AtpAgent<MemorySessionStore, ReqwestClient>;
+
+/// How big the access log can get before it's rotated, if no limit is configured.
+const DEFAULT_ACCESS_LOG_MAX_MB: u64 = 100;
+
+/// How long requests to the bluesky API can take before giving up, if no timeout is configured.
+const DEFAULT_API_TIMEOUT_SECS: u64 = 10;
+
+/// The posts shown on the `/embed-test` page if none are configured, long standing posts from the
+/// official bluesky account that are unlikely to be deleted.
+const DEFAULT_EMBED_TEST_POSTS: &[&str] = &["https://bsky.app/profile/bsky.app/post/3l6oveex3ii2l"];
+
+/// Errors that can occur while assembling the [AppState].
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("No bluesky agent was provided")]
+    MissingAgent,
+    #[error("No base URL was provided")]
+    MissingBaseUrl,
+    #[error("Failed to build an HTTP client: {0}")]
+    HttpClient(#[from] reqwest::Error),
+}
+
+/// Settings for the server process itself rather than its handlers, read before the rest of the
+/// state since some of them have to be applied as early as possible.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// The DSN errors are reported to Sentry with, error reporting is disabled if this isn't set.
+    pub sentry_dsn: Option<String>,
+    /// How many threads rayon's global pool uses for image processing.
+    pub rayon_threads: usize,
+    /// The certificate and key to terminate TLS with, if we aren't behind a reverse proxy doing
+    /// it.
+    pub tls: Option<TlsConfig>,
+    /// Whether the reverse proxy in front of us terminates TLS, so HSTS can be sent.
+    pub behind_tls_proxy: bool,
+    /// Whether to leave out the `X-Powered-By` header.
+    pub hide_powered_by: bool,
+    /// The webhook told when the bluesky session expires, if configured.
+    pub webhook_url: Option<String>,
+}
+
+/// The paths to the PEM files used to terminate TLS.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+impl ServerConfig {
+    /// Reads the server settings from environment variables.
+    pub fn from_env() -> anyhow::Result<Self> {
+        // Rayon would otherwise take every core and leave the async runtime fighting it for CPU
+        // time on small machines.
+        let cores = std::thread::available_parallelism().map_or(1, usize::from);
+
+        let tls = match (
+            env::optional("VXSKY_TLS_CERT"),
+            env::optional("VXSKY_TLS_KEY"),
+        ) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            _ => {
+                return Err(anyhow!(
+                    "Both VXSKY_TLS_CERT and VXSKY_TLS_KEY need to be set to enable TLS."
+                ))
+            }
+        };
+
+        Ok(ServerConfig {
+            sentry_dsn: env::optional("VXSKY_SENTRY_DSN"),
+            rayon_threads: env::parse("VXSKY_RAYON_THREADS", (cores / 2).max(1))?,
+            tls,
+            behind_tls_proxy: env::flag("VXSKY_BEHIND_TLS_PROXY"),
+            hide_powered_by: env::flag("VXSKY_HIDE_POWERED_BY"),
+            webhook_url: env::optional("VXSKY_WEBHOOK_URL"),
+        })
+    }
+}
+
+/// The application state passed to each request handler.
+#[derive(Clone)]
+pub struct AppState {
+    /// The [AtpAgent] used to make requests to the bluesky API, handles authentication and session
+    /// management.
+    pub agent: Arc<Agent>,
+    /// The HTTP client used to make requests for images.
+    pub http_client: Client,
+    /// The HTTP client used to resolve accounts and posts from their PDS, see [resolver::client].
+    pub resolver_client: Client,
+    /// The base URL for where this application is hosted (e.g. "https://vsky.app"). When there are
+    /// multiple base URLs this is the one picked for the current request by [HostState].
+    pub base_url: String,
+    /// Every base URL this application is hosted under, for deployments spread across multiple
+    /// domains. Empty if there's only the one base URL.
+    pub base_urls: Arc<[String]>,
+    /// Options used when generating combined thumbnails.
+    pub processing: ProcessingConfig,
+    /// Counter for how many times each post's embed has been viewed, if Redis is configured.
+    pub views: Option<ViewCounter>,
+    /// Recently rendered combined thumbnails, so they don't need to be generated again.
+    pub image_cache: ImageCache,
+    /// Recently rendered embed pages, so crawlers hitting the same post don't cause it to be
+    /// fetched and rendered again.
+    pub template_cache: TemplateCache,
+    /// Whether to serve stub embeds with fake data instead of calling the bluesky API.
+    pub dry_run: bool,
+    /// Counters for how the server has been doing since it started.
+    pub stats: Stats,
+    /// The hosts this instance can be reached through, requests with any other `Host` header are
+    /// turned away. Every host is allowed if this isn't set.
+    pub allowed_hosts: Option<Vec<String>>,
+    /// Where embed requests are logged in the Apache Combined Log Format, if configured.
+    pub access_log: Option<AccessLog>,
+    /// The bearer token required by the admin endpoints, which are disabled if this isn't set.
+    pub admin_token: Option<String>,
+    /// Links to the posts shown on the `/embed-test` page.
+    pub embed_test_posts: Arc<[String]>,
+    /// The WebSocket connections watching posts for new engagement counts.
+    pub post_updates: PostUpdates,
+}
+
+impl AppState {
+    pub fn builder() -> AppStateBuilder {
+        AppStateBuilder::default()
+    }
+
+    /// A copy of the state with the base URL matching a request's `Host` header, falling back to
+    /// the default base URL if none of them match.
+    pub fn for_host(&self, host: Option<&str>) -> AppState {
+        let matching = host.and_then(|host| {
+            self.base_urls
+                .iter()
+                .find(|url| base_url_matches_host(url, host))
+        });
+
+        match matching {
+            Some(base_url) => AppState {
+                base_url: base_url.to_owned(),
+                ..self.clone()
+            },
+            None => self.clone(),
+        }
+    }
+
+    /// Assembles the application state from environment variables, connecting to any optional
+    /// services and logging in to the bluesky API with the configured account.
+    pub async fn from_env() -> anyhow::Result<AppState> {
+        // Multi-region deployments can list all of their base URLs, the first one being used for
+        // requests that don't match any of them.
+        let base_urls: Vec<String> = env::optional("VXSKY_BASE_URLS")
+            .map(|urls| {
+                urls.split(',')
+                    .map(|url| url.trim().trim_end_matches('/').to_owned())
+                    .filter(|url| !url.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let base_url = match base_urls.first() {
+            Some(base_url) => base_url.to_owned(),
+            None => env::required("VXSKY_BASE_URL")?,
+        };
+
+        let quality = env::parse("VXSKY_IMAGE_QUALITY", ProcessingConfig::default().quality)
+            .ok()
+            .filter(|quality| *quality <= 100)
+            .ok_or_else(|| {
+                anyhow!("The VXSKY_IMAGE_QUALITY environment variable must be 0-100.")
+            })?;
+
+        let output_format = match env::optional("VXSKY_IMAGE_FORMAT") {
+            Some(format) => match ImageFormat::from_extension(format.to_ascii_lowercase()) {
+                Some(format @ (ImageFormat::Png | ImageFormat::Jpeg)) => format,
+                _ => {
+                    return Err(anyhow!(
+                        "The VXSKY_IMAGE_FORMAT environment variable must be png or jpeg."
+                    ))
+                }
+            },
+            None => ProcessingConfig::default().output_format,
+        };
+
+        let processing = ProcessingConfig {
+            crop_to_og_ratio: env::flag("VXSKY_CROP_TO_OG_RATIO"),
+            output_format,
+            quality,
+            blur_radius: env::parse(
+                "VXSKY_THUMBNAIL_BLUR_SIGMA",
+                ProcessingConfig::default().blur_radius,
+            )?,
+            border_width: env::parse("VXSKY_BORDER_WIDTH", 0)?,
+            max_image_bytes: env::parse(
+                "VXSKY_MAX_IMAGE_BYTES",
+                ProcessingConfig::default().max_image_bytes,
+            )?,
+            // Downloads would never start with a limit of zero.
+            max_concurrent_downloads: env::parse(
+                "VXSKY_MAX_CONCURRENT_DOWNLOADS",
+                ProcessingConfig::default().max_concurrent_downloads,
+            )?
+            .max(1),
+            slow_download_threshold: Duration::from_millis(env::parse(
+                "VXSKY_SLOW_DOWNLOAD_THRESHOLD_MS",
+                ProcessingConfig::default()
+                    .slow_download_threshold
+                    .as_millis() as u64,
+            )?),
+            ..Default::default()
+        };
+
+        let cache_capacity = env::parse("VXSKY_IMAGE_CACHE_SIZE", cache::DEFAULT_CAPACITY)?;
+        let template_ttl = env::parse(
+            "VXSKY_TEMPLATE_CACHE_TTL_SECS",
+            cache::DEFAULT_TEMPLATE_TTL.as_secs(),
+        )?;
+        let dry_run = env::flag("VXSKY_DRY_RUN");
+
+        // Without a timeout a hanging API request would hold up its handler forever.
+        let api_timeout = env::parse("VXSKY_API_TIMEOUT_SECS", DEFAULT_API_TIMEOUT_SECS)?;
+        let api_client = Client::builder()
+            .timeout(Duration::from_secs(api_timeout))
+            .build()?;
+
+        let mut builder = AppState::builder()
+            .agent(AtpAgent::new(
+                ReqwestClientBuilder::new("https://bsky.social")
+                    .client(api_client)
+                    .build(),
+                MemorySessionStore::default(),
+            ))
+            .http_client(Client::new())
+            .resolver_client(resolver::client(Duration::from_secs(api_timeout))?)
+            .base_url(base_url)
+            .base_urls(base_urls)
+            .processing(processing)
+            .image_cache(ImageCache::new(cache_capacity))
+            .template_cache(TemplateCache::new(Duration::from_secs(template_ttl)))
+            .post_updates(PostUpdates::new(
+                env::parse("VXSKY_MAX_WEBSOCKETS", updates::DEFAULT_MAX_CONNECTIONS)?,
+                &env::optional("VXSKY_FIREHOSE_URL")
+                    .unwrap_or_else(|| updates::DEFAULT_FIREHOSE_URL.to_owned()),
+            ))
+            .dry_run(dry_run);
+
+        if let Some(hosts) = env::optional("VXSKY_ALLOWED_HOSTS") {
+            builder = builder.allowed_hosts(hosts.split(',').map(str::trim));
+        }
+
+        // Setting this replaces the default posts entirely, an empty value leaves none.
+        if let Some(posts) = env::optional("VXSKY_EMBED_TEST_POSTS") {
+            builder = builder.embed_test_posts(
+                posts
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|post| !post.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+            );
+        }
+
+        if let Some(token) = env::optional("VXSKY_ADMIN_TOKEN") {
+            builder = builder.admin_token(token);
+        }
+
+        if let Some(path) = env::optional("VXSKY_ACCESS_LOG_FILE") {
+            let max_mb = env::parse("VXSKY_ACCESS_LOG_MAX_MB", DEFAULT_ACCESS_LOG_MAX_MB)?;
+            builder = builder.access_log(AccessLog::open(path, max_mb * 1024 * 1024).await?);
+        }
+
+        // View counting is optional and only enabled when a Redis instance is provided.
+        if let Some(url) = env::optional("VXSKY_REDIS_URL") {
+            builder = builder.views(ViewCounter::connect(&url).await?);
+        }
+
+        let state = builder.build()?;
+
+        // Nothing talks to the bluesky API in dry-run mode, so there's no need to log in.
+        if dry_run {
+            warn!("Dry-run mode is enabled, serving stub embeds without calling the bluesky API");
+            return Ok(state);
+        }
+
+        // Get Bluesky account credentials for API access.
+        let identifier = env::optional("VXSKY_IDENTIFIER").ok_or_else(|| {
+            anyhow!(
+                "The VXSKY_IDENTIFIER environment variable is required, either an email or handle."
+            )
+        })?;
+        let password = env::required("VXSKY_APP_PASSWORD")?;
+
+        // Authenticate with the bluesky API and store the session.
+        state.agent.login(identifier, password).await?;
+
+        Ok(state)
+    }
+}
+
+/// The host a request was made to, from its `Host` header or, for HTTP/2 requests which don't send
+/// one, the `:authority` pseudo-header that ends up in the URI.
+pub fn request_host(headers: &HeaderMap, uri: &Uri) -> Option<String> {
+    headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .or_else(|| {
+            uri.authority()
+                .map(|authority| authority.as_str().to_owned())
+        })
+}
+
+/// Whether a base URL (e.g. "https://vxsky.app") is for the given `Host` header value.
+pub fn base_url_matches_host(base_url: &str, host: &str) -> bool {
+    let url_host = base_url
+        .split_once("://")
+        .map_or(base_url, |(_, rest)| rest);
+    url_host.eq_ignore_ascii_case(host)
+}
+
+/// Extractor for the [AppState] with the base URL picked for the host of the request, used by
+/// handlers in place of [State](axum::extract::State) so links point back at whichever domain the
+/// request came in on.
+pub struct HostState(pub AppState);
+
+#[async_trait]
+impl FromRequestParts<AppState> for HostState {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let host = request_host(&parts.headers, &parts.uri);
+        Ok(HostState(state.for_host(host.as_deref())))
+    }
+}
+
+/// Builder for [AppState], so it can be put together piece by piece without reading any
+/// environment variables.
+#[derive(Default)]
+pub struct AppStateBuilder {
+    agent: Option<Arc<Agent>>,
+    http_client: Option<Client>,
+    resolver_client: Option<Client>,
+    base_url: Option<String>,
+    base_urls: Vec<String>,
+    processing: Option<ProcessingConfig>,
+    views: Option<ViewCounter>,
+    image_cache: Option<ImageCache>,
+    template_cache: Option<TemplateCache>,
+    dry_run: bool,
+    allowed_hosts: Option<Vec<String>>,
+    access_log: Option<AccessLog>,
+    admin_token: Option<String>,
+    embed_test_posts: Option<Vec<String>>,
+    post_updates: Option<PostUpdates>,
+}
+
+impl AppStateBuilder {
+    pub fn agent(mut self, agent: impl Into<Arc<Agent>>) -> Self {
+        self.agent = Some(agent.into());
+        self
+    }
+
+    /// Sets the HTTP client used for downloading images, defaults to [Client::new].
+    pub fn http_client(mut self, http_client: Client) -> Self {
+        self.http_client = Some(http_client);
+        self
+    }
+
+    /// Sets the HTTP client used for resolving from PDS instances, defaults to [resolver::client]
+    /// with the default API timeout.
+    pub fn resolver_client(mut self, resolver_client: Client) -> Self {
+        self.resolver_client = Some(resolver_client);
+        self
+    }
+
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = Some(base_url.into());
+        self
+    }
+
+    /// Sets every base URL the application is hosted under, in addition to the default
+    /// [AppStateBuilder::base_url].
+    pub fn base_urls(mut self, base_urls: Vec<String>) -> Self {
+        self.base_urls = base_urls;
+        self
+    }
+
+    /// Sets the thumbnail processing options, defaults to [ProcessingConfig::default].
+    pub fn processing(mut self, processing: ProcessingConfig) -> Self {
+        self.processing = Some(processing);
+        self
+    }
+
+    /// Enables view counting for embeds, disabled by default.
+    pub fn views(mut self, views: ViewCounter) -> Self {
+        self.views = Some(views);
+        self
+    }
+
+    /// Sets the cache used for combined thumbnails, defaults to one holding
+    /// [cache::DEFAULT_CAPACITY] thumbnails.
+    pub fn image_cache(mut self, image_cache: ImageCache) -> Self {
+        self.image_cache = Some(image_cache);
+        self
+    }
+
+    /// Sets the cache used for rendered embed pages, defaults to one keeping pages for
+    /// [cache::DEFAULT_TEMPLATE_TTL].
+    pub fn template_cache(mut self, template_cache: TemplateCache) -> Self {
+        self.template_cache = Some(template_cache);
+        self
+    }
+
+    /// Enables dry-run mode, disabled by default.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Restricts which `Host` headers are accepted, every host is allowed by default. Hosts are
+    /// compared case-insensitively and empty entries are ignored.
+    pub fn allowed_hosts<I, S>(mut self, hosts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let hosts = hosts
+            .into_iter()
+            .map(|host| host.as_ref().to_ascii_lowercase())
+            .filter(|host| !host.is_empty())
+            .collect();
+        self.allowed_hosts = Some(hosts);
+        self
+    }
+
+    /// Enables the access log for embed requests, disabled by default.
+    pub fn access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// Enables the admin endpoints behind the given bearer token, disabled by default.
+    pub fn admin_token(mut self, admin_token: impl Into<String>) -> Self {
+        self.admin_token = Some(admin_token.into());
+        self
+    }
+
+    /// Sets the links to the posts shown on the `/embed-test` page, defaults to
+    /// [DEFAULT_EMBED_TEST_POSTS].
+    pub fn embed_test_posts(mut self, embed_test_posts: Vec<String>) -> Self {
+        self.embed_test_posts = Some(embed_test_posts);
+        self
+    }
+
+    /// Sets how post updates are shared between WebSocket connections, defaults to allowing up to
+    /// [updates::DEFAULT_MAX_CONNECTIONS] connections and following the firehose from
+    /// [updates::DEFAULT_FIREHOSE_URL].
+    pub fn post_updates(mut self, post_updates: PostUpdates) -> Self {
+        self.post_updates = Some(post_updates);
+        self
+    }
+
+    pub fn build(self) -> Result<AppState, ConfigError> {
+        Ok(AppState {
+            agent: self.agent.ok_or(ConfigError::MissingAgent)?,
+            http_client: self.http_client.unwrap_or_default(),
+            resolver_client: match self.resolver_client {
+                Some(client) => client,
+                None => resolver::client(Duration::from_secs(DEFAULT_API_TIMEOUT_SECS))?,
+            },
+            base_url: self.base_url.ok_or(ConfigError::MissingBaseUrl)?,
+            base_urls: self.base_urls.into(),
+            processing: self.processing.unwrap_or_default(),
+            views: self.views,
+            image_cache: self.image_cache.unwrap_or_default(),
+            template_cache: self.template_cache.unwrap_or_default(),
+            dry_run: self.dry_run,
+            stats: Stats::default(),
+            allowed_hosts: self.allowed_hosts,
+            access_log: self.access_log,
+            admin_token: self.admin_token,
+            embed_test_posts: match self.embed_test_posts {
+                Some(posts) => posts.into(),
+                None => DEFAULT_EMBED_TEST_POSTS
+                    .iter()
+                    .map(|post| post.to_string())
+                    .collect(),
+            },
+            post_updates: self.post_updates.unwrap_or_default(),
+        })
+    }
+}
@@ -0,0 +1,177 @@
+//! Runtime statistics that can be dumped to the log on demand, without restarting the server.
+
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::info;
+
+use crate::state::AppState;
+
+/// The upper bounds of the request latency histogram buckets in milliseconds, anything slower than
+/// the last one lands in an overflow bucket.
+const LATENCY_BUCKETS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Counters updated while serving requests.
+#[derive(Clone, Default)]
+pub struct Stats {
+    requests_served: Arc<AtomicU64>,
+    latency: Arc<LatencyHistogram>,
+}
+
+impl Stats {
+    pub fn record_request(&self) {
+        self.requests_served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn requests_served(&self) -> u64 {
+        self.requests_served.load(Ordering::Relaxed)
+    }
+
+    /// Records how long a request took to handle.
+    pub fn record_latency(&self, elapsed: Duration) {
+        self.latency.record(elapsed);
+    }
+
+    /// Estimates the latency percentile `p` (from 0 to 1) in milliseconds, or `None` if no requests
+    /// have been timed yet.
+    pub fn latency_percentile(&self, p: f64) -> Option<u64> {
+        self.latency.percentile(p)
+    }
+
+    /// Renders the statistics in the Prometheus text exposition format.
+    pub fn prometheus(&self) -> String {
+        let mut output = String::new();
+
+        output.push_str("# HELP vxsky_requests_total Requests served since the server started.\n");
+        output.push_str("# TYPE vxsky_requests_total counter\n");
+        let _ = writeln!(output, "vxsky_requests_total {}", self.requests_served());
+
+        output
+            .push_str("# HELP vxsky_request_duration_seconds How long requests took to handle.\n");
+        output.push_str("# TYPE vxsky_request_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(self.latency.counts()) {
+            cumulative += count;
+            let bound = *bound as f64 / 1000.0;
+            let _ = writeln!(
+                output,
+                "vxsky_request_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+            );
+        }
+
+        let count = self.latency.count();
+        let sum = self.latency.sum_ms.load(Ordering::Relaxed) as f64 / 1000.0;
+        let _ = writeln!(
+            output,
+            "vxsky_request_duration_seconds_bucket{{le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(output, "vxsky_request_duration_seconds_sum {sum}");
+        let _ = writeln!(output, "vxsky_request_duration_seconds_count {count}");
+
+        output
+    }
+}
+
+/// A histogram of request latencies, made of counters for each of the [LATENCY_BUCKETS_MS] plus an
+/// overflow bucket, so it can be updated from every request without any locking.
+#[derive(Default)]
+struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    sum_ms: AtomicU64,
+}
+
+impl LatencyHistogram {
+    fn record(&self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    /// The number of requests in each bucket, including the overflow bucket.
+    fn counts(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    fn count(&self) -> u64 {
+        self.counts().iter().sum()
+    }
+
+    /// Estimates a percentile as the upper bound of the bucket it falls in. Requests in the
+    /// overflow bucket are reported as the largest bound, since there's nothing better to go on.
+    fn percentile(&self, p: f64) -> Option<u64> {
+        let counts = self.counts();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+
+        let target = ((total as f64 * p.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut cumulative = 0;
+        for (index, count) in counts.iter().enumerate() {
+            cumulative += count;
+            if cumulative >= target {
+                let bound = LATENCY_BUCKETS_MS.get(index).or(LATENCY_BUCKETS_MS.last());
+                return bound.copied();
+            }
+        }
+
+        LATENCY_BUCKETS_MS.last().copied()
+    }
+}
+
+/// Logs the current statistics every time the process receives `SIGUSR1`.
+#[cfg(unix)]
+pub async fn log_on_sigusr1(state: AppState) -> std::io::Result<()> {
+    use tokio::signal::unix::{
+        signal,
+        SignalKind,
+    };
+
+    let mut signals = signal(SignalKind::user_defined1())?;
+    while signals.recv().await.is_some() {
+        log_stats(&state).await;
+    }
+
+    Ok(())
+}
+
+/// Logs the cache sizes, how many requests have been served and whether our bluesky session is
+/// still valid.
+#[cfg(unix)]
+async fn log_stats(state: &AppState) {
+    // Checking the session needs an API call, but this only happens when someone asks for it.
+    let session_valid = state
+        .agent
+        .api
+        .com
+        .atproto
+        .server
+        .get_session()
+        .await
+        .is_ok();
+
+    info!(
+        "Stats: {} cached thumbnails, {} cached embed pages, {} requests served, session valid: {}",
+        state.image_cache.len().await,
+        state.template_cache.len(),
+        state.stats.requests_served(),
+        session_valid,
+    );
+}
@@ -1,9 +1,31 @@
 //! HTML templates used to render the meta embed tags for embed cards.
 
+use std::ops::Deref;
+
 use askama::Template;
-use atrium_api::app::bsky::{
-    actor::defs::ProfileViewBasic,
-    feed::post,
+use atrium_api::{
+    app::bsky::{
+        actor::defs::ProfileViewBasic,
+        feed::{
+            defs::PostView,
+            post,
+        },
+    },
+    records::Record,
+};
+use percent_encoding::{
+    utf8_percent_encode,
+    NON_ALPHANUMERIC,
+};
+
+use crate::{
+    api::{
+        combined_image_url,
+        embed_title,
+        oembed_url,
+        JsonLdArticle,
+    },
+    embed::post_images,
 };
 
 /// The HTML template used to present meta embed tags to different services.
@@ -20,6 +42,302 @@ pub struct ImageEmbed {
     pub post_url: String,
     /// The atproto record for the post, containing the posts content.
     pub record: Box<post::Record>,
+    /// Whether to include the extra OpenGraph tags used by Matrix link previews.
+    pub matrix_preview: bool,
+    /// How many times this post's embed had been viewed when the page was rendered, if view
+    /// counting is enabled. Cached pages aren't rendered again for every view, so this can be
+    /// behind the real count by as much as the template cache lets a page live.
+    pub view_count: Option<u64>,
+    /// The content warning label on the post or its author, if there is one.
+    pub content_warning: Option<String>,
+}
+
+impl ImageEmbed {
+    /// The language of the post's content.
+    pub fn lang(&self) -> &str {
+        post_lang(&self.record)
+    }
+
+    /// The link to the combined thumbnail for the post.
+    pub fn image_url(&self) -> String {
+        combined_image_url(&self.base_url, &self.aturi)
+    }
+
+    /// The description shown on the embed card, the post's text.
+    pub fn description(&self) -> &str {
+        &self.record.text
+    }
+
+    /// The link to the oEmbed response for the post.
+    pub fn oembed_url(&self) -> String {
+        oembed_url(&self.base_url, &self.post_url)
+    }
+
+    /// The JSON-LD structured data for the post, ready to be put in a `<script>` tag.
+    pub fn json_ld(&self) -> String {
+        JsonLdArticle::new(
+            &self.profile,
+            &self.record,
+            &self.content_warning,
+            self.post_url.to_owned(),
+            Some(self.image_url()),
+        )
+        .to_script()
+    }
+
+    /// The link to the AMP version of the embed page.
+    pub fn amp_url(&self) -> String {
+        let path = self.post_url.trim_start_matches("https://bsky.app");
+        format!("{}{path}/amp", self.base_url)
+    }
+}
+
+/// Languages that have a localized variant of the [ImageEmbed] template, English is the default
+/// and is used for everything else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Locale {
+    Spanish,
+    Japanese,
+}
+
+impl Locale {
+    /// Picks the locale for a BCP 47 language tag (e.g. "es-MX"), going by its primary language
+    /// subtag. Returns `None` if there's no localized template for the language.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match primary_subtag(tag).as_str() {
+            "es" => Some(Locale::Spanish),
+            "ja" => Some(Locale::Japanese),
+            _ => None,
+        }
+    }
+
+    /// Whether this locale suits a post written in the given languages. Posts that don't say what
+    /// language they're in are compatible with every locale.
+    pub fn is_compatible(self, langs: Option<&Vec<String>>) -> bool {
+        match langs {
+            Some(langs) if !langs.is_empty() => langs
+                .iter()
+                .any(|lang| Locale::from_tag(lang) == Some(self)),
+            _ => true,
+        }
+    }
+}
+
+/// The lowercase primary language subtag of a BCP 47 language tag, e.g. "pt" for "pt-BR".
+fn primary_subtag(tag: &str) -> String {
+    tag.split(['-', '_'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// The Spanish variant of the [ImageEmbed] template.
+#[derive(Template)]
+#[template(path = "embed_images.es.html")]
+pub struct SpanishImageEmbed<'a> {
+    pub embed: &'a ImageEmbed,
+}
+
+/// The Japanese variant of the [ImageEmbed] template.
+#[derive(Template)]
+#[template(path = "embed_images.ja.html")]
+pub struct JapaneseImageEmbed<'a> {
+    pub embed: &'a ImageEmbed,
+}
+
+// The localized templates extend the default one, so derefing to the wrapped embed lets them use
+// its fields and methods as-is.
+impl Deref for SpanishImageEmbed<'_> {
+    type Target = ImageEmbed;
+
+    fn deref(&self) -> &Self::Target {
+        self.embed
+    }
+}
+
+impl Deref for JapaneseImageEmbed<'_> {
+    type Target = ImageEmbed;
+
+    fn deref(&self) -> &Self::Target {
+        self.embed
+    }
+}
+
+/// The AMP variant of the [ImageEmbed] template, for search engines that prefer AMP pages for
+/// previews.
+#[derive(Template)]
+#[template(path = "embed_images_amp.html")]
+pub struct AmpImageEmbed<'a> {
+    pub embed: &'a ImageEmbed,
+}
+
+impl AmpImageEmbed<'_> {
+    /// The title of the post, the author's display name and handle.
+    pub fn title(&self) -> String {
+        embed_title(&self.embed.profile)
+    }
+}
+
+impl Deref for AmpImageEmbed<'_> {
+    type Target = ImageEmbed;
+
+    fn deref(&self) -> &Self::Target {
+        self.embed
+    }
+}
+
+impl ImageEmbed {
+    /// Renders the embed using the localized template for a locale, or the default template if no
+    /// locale was picked.
+    pub fn render_localized(&self, locale: Option<Locale>) -> askama::Result<String> {
+        match locale {
+            Some(Locale::Spanish) => SpanishImageEmbed { embed: self }.render(),
+            Some(Locale::Japanese) => JapaneseImageEmbed { embed: self }.render(),
+            None => self.render(),
+        }
+    }
+}
+
+/// The HTML template used to present meta embed tags for posts that only have text, using a
+/// placeholder image in place of the combined thumbnail.
+#[derive(Template)]
+#[template(path = "embed_text.html")]
+pub struct TextEmbed {
+    /// The profile of the user who made the post.
+    pub profile: ProfileViewBasic,
+    /// The base URL of this application, used for links.
+    pub base_url: String,
+    /// The human clickable link to the post.
+    pub post_url: String,
+    /// The atproto record for the post, containing the posts content.
+    pub record: Box<post::Record>,
+    /// Whether to include the extra OpenGraph tags used by Matrix link previews.
+    pub matrix_preview: bool,
+    /// How many times this post's embed had been viewed when the page was rendered, if view
+    /// counting is enabled.
+    pub view_count: Option<u64>,
+    /// The content warning label on the post or its author, if there is one.
+    pub content_warning: Option<String>,
+}
+
+impl TextEmbed {
+    /// The language of the post's content.
+    pub fn lang(&self) -> &str {
+        post_lang(&self.record)
+    }
+
+    /// The link to the placeholder image used in place of a combined thumbnail.
+    pub fn image_url(&self) -> String {
+        format!("{}/text-only.png", self.base_url)
+    }
+
+    /// The description shown on the embed card, the post's text.
+    pub fn description(&self) -> &str {
+        &self.record.text
+    }
+
+    /// The link to the oEmbed response for the post.
+    pub fn oembed_url(&self) -> String {
+        oembed_url(&self.base_url, &self.post_url)
+    }
+}
+
+/// The HTML template used to present meta embed tags for a post along with the conversation around
+/// it, with the ancestors and replies in the description.
+#[derive(Template)]
+#[template(path = "embed_thread.html")]
+pub struct ThreadEmbed {
+    /// The profile of the user who made the post.
+    pub profile: ProfileViewBasic,
+    /// The human clickable link to the post.
+    pub post_url: String,
+    /// The atproto record for the post, containing the posts content.
+    pub record: Box<post::Record>,
+    /// Whether to include the extra OpenGraph tags used by Matrix link previews.
+    pub matrix_preview: bool,
+    /// How many times this post's embed had been viewed when the page was rendered, if view
+    /// counting is enabled.
+    pub view_count: Option<u64>,
+    /// The content warning label on the post or its author, if there is one.
+    pub content_warning: Option<String>,
+    /// The link to the image shown in the embed card.
+    pub image_url: String,
+    /// The conversation around the post, shown as the description.
+    pub thread: String,
+}
+
+impl ThreadEmbed {
+    /// The language of the post's content.
+    pub fn lang(&self) -> &str {
+        post_lang(&self.record)
+    }
+
+    pub fn image_url(&self) -> &str {
+        &self.image_url
+    }
+
+    /// The description shown on the embed card, the conversation around the post.
+    pub fn description(&self) -> &str {
+        &self.thread
+    }
+}
+
+/// Just the OpenGraph and Twitter card meta tags of an embed without the rest of the page, for
+/// services that parse the tags out of a fragment and for checking what tags a post would get.
+#[derive(Template)]
+#[template(path = "opengraph.html")]
+pub struct OpenGraphTags {
+    /// The profile of the user who made the post.
+    pub profile: ProfileViewBasic,
+    /// The human clickable link to the post.
+    pub post_url: String,
+    /// The atproto record for the post, containing the posts content.
+    pub record: Box<post::Record>,
+    /// Whether to include the extra OpenGraph tags used by Matrix link previews.
+    pub matrix_preview: bool,
+    /// How many times this post's embed had been viewed when the page was rendered, if view
+    /// counting is enabled.
+    pub view_count: Option<u64>,
+    /// The content warning label on the post or its author, if there is one.
+    pub content_warning: Option<String>,
+    /// The link to the image shown in the embed card.
+    pub image_url: String,
+}
+
+impl OpenGraphTags {
+    pub fn image_url(&self) -> &str {
+        &self.image_url
+    }
+
+    /// The description shown on the embed card, the post's text.
+    pub fn description(&self) -> &str {
+        &self.record.text
+    }
+}
+
+/// Only the Twitter card meta tags of an embed, for Twitter's Card Validator and other tools that
+/// just want the `twitter:*` tags.
+#[derive(Template)]
+#[template(path = "twitter_card.html")]
+pub struct TwitterCard {
+    /// The card type, `summary_large_image` for posts with images and `summary` for everything
+    /// else.
+    pub card: &'static str,
+    pub title: String,
+    pub description: String,
+    /// The link to the image shown in the card.
+    pub image_url: String,
+}
+
+/// The language of a post's content, taken from the first language the post is tagged with.
+/// Defaults to English if the post has no languages set.
+fn post_lang(record: &post::Record) -> &str {
+    record
+        .langs
+        .as_ref()
+        .and_then(|langs| langs.first())
+        .map(String::as_str)
+        .unwrap_or("en")
 }
 
 /// The HTML template used to present meta embed tags to different services.
@@ -32,4 +350,174 @@ pub struct EmbedAccountGated {
     pub base_url: String,
     /// The human clickable link to the post.
     pub post_url: String,
+    /// Whether to include the extra OpenGraph tags used by Matrix link previews.
+    pub matrix_preview: bool,
+}
+
+/// The HTML page returned to real people, which refreshes straight to the post and shows a link
+/// for anyone with refreshes disabled or on a slow connection.
+#[derive(Template)]
+#[template(path = "redirect.html")]
+pub struct PostRedirect {
+    /// The human clickable link to the post.
+    pub post_url: String,
+}
+
+/// A JavaScript snippet that can be included with a `<script>` tag on an external page, which
+/// inserts an `<iframe>` of the post's embed card right where the script tag is.
+#[derive(Template)]
+#[template(path = "embed.js", escape = "none")]
+pub struct ScriptEmbed {
+    /// The link to the post's framable embed card, percent-encoded so it is safe to put in a JS
+    /// string.
+    pub embed_url: String,
+}
+
+/// The HTML page returned when a request fails, with the error in the embed card description so it
+/// shows up when the link is shared.
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct ErrorPage {
+    /// The HTTP status code of the response.
+    pub code: u16,
+    /// A human readable description of what went wrong.
+    pub message: String,
+}
+
+/// A page showing a mock Discord style card for a post's embed, along with the HTML the embed page
+/// is made of, so embeds can be checked without sharing the link somewhere.
+#[derive(Template)]
+#[template(path = "preview.html")]
+pub struct EmbedPreview {
+    /// The human clickable link to the post.
+    pub post_url: String,
+    /// The title shown on the card.
+    pub title: String,
+    /// The description shown on the card.
+    pub description: String,
+    /// The image shown on the card.
+    pub image_url: String,
+    /// The rendered embed page, shown as source.
+    pub embed_html: String,
+}
+
+/// Just the embed card of a post, for embeds on other sites to put in an `<iframe>`. The card links
+/// to the post on bluesky, opening it in a new tab since bluesky itself can't be framed.
+#[derive(Template)]
+#[template(path = "embed_frame.html")]
+pub struct EmbedFrame {
+    /// The human clickable link to the post.
+    pub post_url: String,
+    /// The title shown on the card.
+    pub title: String,
+    /// The description shown on the card.
+    pub description: String,
+    /// The image shown on the card.
+    pub image_url: String,
+}
+
+/// A share sheet for a post, with a preview of its embed card, buttons for copying and sharing the
+/// link and a QR code for it.
+#[derive(Template)]
+#[template(path = "share.html")]
+pub struct SharePage {
+    /// The base URL of this application, used for links.
+    pub base_url: String,
+    /// The link being shared, the post on this instance so it gets an embed wherever it ends up.
+    pub share_url: String,
+    /// The title shown on the card.
+    pub title: String,
+    /// The description shown on the card.
+    pub description: String,
+    /// The image shown on the card.
+    pub image_url: String,
+    /// The QR code for [SharePage::share_url], as an inline SVG.
+    pub qr_code: String,
+}
+
+impl SharePage {
+    /// The link for composing a new bluesky post with the shared link in it, used by the share
+    /// button when the browser has no share sheet of its own.
+    pub fn compose_url(&self) -> String {
+        let text = utf8_percent_encode(&self.share_url, NON_ALPHANUMERIC);
+        format!("https://bsky.app/intent/compose?text={text}")
+    }
+}
+
+/// A developer page showing the embed previews of a list of test posts, for checking that templates
+/// and compositing still work after making changes.
+#[derive(Template)]
+#[template(path = "embed_test.html")]
+pub struct EmbedTest {
+    pub posts: Vec<EmbedTestPost>,
+}
+
+/// A single test post on the [EmbedTest] page.
+pub struct EmbedTestPost {
+    /// The link to the post on bluesky.
+    pub post_url: String,
+    /// The link to the post's embed preview page on this instance.
+    pub preview_url: String,
+}
+
+/// The HTML page returned for paths that don't match any route, pointing people at how vxsky
+/// links are meant to look.
+#[derive(Template)]
+#[template(path = "not_found.html")]
+pub struct NotFoundPage {
+    /// The path that was requested.
+    pub path: String,
+}
+
+/// The HTML template used to present search results, with an embed card for the top result.
+#[derive(Template)]
+#[template(path = "search_results.html")]
+pub struct SearchResults {
+    /// The query that was searched for.
+    pub query: String,
+    /// The most popular post in the results, used for the embed card.
+    pub top: SearchResult,
+    /// The rest of the results, shown as links to their own embeds.
+    pub others: Vec<SearchResult>,
+}
+
+/// A single post shown in [SearchResults].
+pub struct SearchResult {
+    /// The title of the post's embed card, the author's display name and handle.
+    pub title: String,
+    /// The text content of the post.
+    pub text: String,
+    /// The link to this post's embed on this application.
+    pub embed_url: String,
+    /// The combined thumbnail for the post, if it has any images.
+    pub image_url: Option<String>,
+    /// How many likes the post has, used to pick the top result.
+    pub like_count: i32,
+}
+
+impl SearchResult {
+    /// Builds a search result from a post, returns `None` if the post isn't a regular post record.
+    /// Posts with a content warning have their text replaced with the warning, their thumbnail is
+    /// obscured by the rendering endpoint itself.
+    pub fn new(view: &PostView, base_url: &str, content_warning: Option<&str>) -> Option<Self> {
+        let Record::AppBskyFeedPost(record) = &view.record else {
+            return None;
+        };
+
+        let post_id = view.uri.rsplit('/').next()?;
+        let image_url = post_images(view).map(|_| combined_image_url(base_url, &view.uri));
+
+        let text = match content_warning {
+            Some(label) => format!("Content Warning ({label}): click to reveal"),
+            None => record.text.to_owned(),
+        };
+
+        Some(SearchResult {
+            title: embed_title(&view.author),
+            text,
+            embed_url: format!("{base_url}/profile/{}/post/{post_id}", view.author.handle),
+            image_url,
+            like_count: view.like_count.unwrap_or_default(),
+        })
+    }
 }
@@ -0,0 +1,87 @@
+//! Helpers for describing the conversation around a post, used by thread embeds.
+
+use atrium_api::{
+    app::bsky::feed::defs::{
+        PostView,
+        ThreadViewPost,
+        ThreadViewPostParentEnum,
+        ThreadViewPostRepliesItem,
+    },
+    records::Record,
+};
+
+use crate::requires_authentication;
+
+/// How many of the posts directly above the embedded post are shown.
+pub const MAX_ANCESTORS: usize = 2;
+/// How many replies to the embedded post are shown.
+pub const MAX_REPLIES: usize = 2;
+
+/// Builds the description for a thread embed, the root of the thread and the nearest ancestors
+/// above the post, then the post itself, followed by the first few replies to it. The root is only
+/// passed in if it isn't one of the ancestors already in the thread.
+pub fn thread_description(thread: &ThreadViewPost, root: Option<&PostView>) -> String {
+    let mut ancestors = Vec::new();
+    let mut parent = thread.parent.as_ref();
+    while let Some(ThreadViewPostParentEnum::ThreadViewPost(view)) = parent {
+        if ancestors.len() == MAX_ANCESTORS {
+            break;
+        }
+        ancestors.push(&view.post);
+        parent = view.parent.as_ref();
+    }
+
+    let mut lines = Vec::new();
+    if let Some(root) = root {
+        lines.push(post_line(root));
+        lines.push("…".to_owned());
+    }
+    lines.extend(ancestors.iter().rev().map(|post| post_line(post)));
+    lines.push(post_line(&thread.post));
+
+    let replies = thread
+        .replies
+        .iter()
+        .flatten()
+        .filter_map(|reply| match reply {
+            ThreadViewPostRepliesItem::ThreadViewPost(reply) => Some(&reply.post),
+            _ => None,
+        })
+        .filter(|reply| !requires_authentication(&reply.author));
+    lines.extend(
+        replies
+            .take(MAX_REPLIES)
+            .map(|reply| format!("↳ {}", post_line(reply))),
+    );
+
+    lines.join("\n\n")
+}
+
+/// Whether the root of the thread is already one of the ancestors shown in the description.
+pub fn shows_root(thread: &ThreadViewPost, root_uri: &str) -> bool {
+    let mut parent = thread.parent.as_ref();
+    for _ in 0..MAX_ANCESTORS {
+        match parent {
+            Some(ThreadViewPostParentEnum::ThreadViewPost(view)) if view.post.uri == root_uri => {
+                return true
+            }
+            Some(ThreadViewPostParentEnum::ThreadViewPost(view)) => parent = view.parent.as_ref(),
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// A single post in the description, its author's handle followed by its text. Posts from accounts
+/// that ask to only be shown to signed in users are redacted entirely.
+fn post_line(post: &PostView) -> String {
+    if requires_authentication(&post.author) {
+        return "[post from an account that requires signing in]".to_owned();
+    }
+
+    let text = match &post.record {
+        Record::AppBskyFeedPost(record) => record.text.as_str(),
+        _ => "",
+    };
+    format!("@{}: {text}", post.author.handle)
+}
@@ -0,0 +1,127 @@
+//! Sharing of post engagement updates between the WebSocket connections watching the same post.
+//! Counts are kept up to date from the firehose, which is only followed while something is
+//! watching.
+
+use std::sync::{
+    atomic::{
+        AtomicBool,
+        Ordering,
+    },
+    Arc,
+};
+
+use dashmap::DashMap;
+use tokio::sync::{
+    watch,
+    OwnedSemaphorePermit,
+    Semaphore,
+};
+
+use crate::{
+    api::PostCounts,
+    firehose::{
+        self,
+        CountChange,
+    },
+};
+
+/// How many WebSocket connections can be open at once, if no limit is configured.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1000;
+
+/// The relay the firehose is followed from, if no other is configured.
+pub const DEFAULT_FIREHOSE_URL: &str = "wss://bsky.network";
+
+/// The latest counts of a post.
+pub type CountsReceiver = watch::Receiver<PostCounts>;
+
+/// The posts being watched over WebSockets, keyed by ATUri, and the firehose connection keeping
+/// their counts up to date.
+#[derive(Clone)]
+pub struct PostUpdates {
+    watched: Arc<DashMap<String, watch::Sender<PostCounts>>>,
+    connections: Arc<Semaphore>,
+    firehose_url: Arc<str>,
+    firehose_running: Arc<AtomicBool>,
+}
+
+impl PostUpdates {
+    pub fn new(max_connections: usize, firehose_url: &str) -> Self {
+        PostUpdates {
+            watched: Arc::new(DashMap::new()),
+            connections: Arc::new(Semaphore::new(max_connections)),
+            firehose_url: firehose_url.trim_end_matches('/').into(),
+            firehose_running: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Takes one of the connection slots, returning `None` if they're all in use. The slot is given
+    /// back when the permit is dropped.
+    pub fn connect(&self) -> Option<OwnedSemaphorePermit> {
+        self.connections.clone().try_acquire_owned().ok()
+    }
+
+    /// Subscribes to a post's counts, starting from `counts` if nothing was watching the post yet,
+    /// and connects to the firehose if it isn't being followed already.
+    pub fn subscribe(&self, aturi: &str, counts: PostCounts) -> CountsReceiver {
+        let receiver = self
+            .watched
+            .entry(aturi.to_owned())
+            .or_insert_with(|| watch::channel(counts).0)
+            .subscribe();
+
+        if self
+            .firehose_running
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            tokio::spawn(firehose::run(self.clone()));
+        }
+
+        receiver
+    }
+
+    /// Stops watching a post if no connections are left watching it, to be called once a
+    /// connection has dropped its receiver.
+    pub fn unsubscribe(&self, aturi: &str) {
+        self.watched
+            .remove_if(aturi, |_, sender| sender.receiver_count() == 0);
+    }
+
+    /// Applies a change from the firehose to a post's counts, if it's being watched.
+    pub fn apply(&self, aturi: &str, change: CountChange) {
+        if let Some(sender) = self.watched.get(aturi) {
+            sender.send_modify(|counts| match change {
+                CountChange::Like => counts.like_count += 1,
+                CountChange::Repost => counts.repost_count += 1,
+                CountChange::Reply => counts.reply_count += 1,
+            });
+        }
+    }
+
+    /// Whether any posts are being watched.
+    pub fn is_watching(&self) -> bool {
+        !self.watched.is_empty()
+    }
+
+    /// The relay the firehose is followed from.
+    pub fn firehose_url(&self) -> &str {
+        &self.firehose_url
+    }
+
+    /// Marks the firehose as no longer followed, returning whether the caller should disconnect.
+    /// A post watched in the meantime may not have seen it running, so it's taken back over then.
+    pub fn stop_firehose(&self) -> bool {
+        self.firehose_running.store(false, Ordering::Release);
+        !(self.is_watching()
+            && self
+                .firehose_running
+                .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok())
+    }
+}
+
+impl Default for PostUpdates {
+    fn default() -> Self {
+        PostUpdates::new(DEFAULT_MAX_CONNECTIONS, DEFAULT_FIREHOSE_URL)
+    }
+}
@@ -50,16 +50,56 @@ where
     async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
         if let Some(user_agent) = parts.headers.get(USER_AGENT) {
             let agent = user_agent.to_str().unwrap();
-            let in_embed_list = IMAGE_EMBED_USERAGENTS.contains(&agent);
-
-            // WhatsApp useragents are weird, we just check for the word to cover all bases.
-            let whatsapp_in_agent = agent.contains("WhatsApp/");
-            match (in_embed_list, whatsapp_in_agent) {
-                (true, _) | (_, true) => Ok(RequireEmbed(Some(user_agent.to_owned()))),
-                _ => Ok(RequireEmbed(None)),
+            match matched_rule(agent) {
+                Some(_) => Ok(RequireEmbed(Some(user_agent.to_owned()))),
+                None => Ok(RequireEmbed(None)),
             }
         } else {
             Err((StatusCode::BAD_REQUEST, "`User-Agent` header is missing"))
         }
     }
 }
+
+/// Finds which rule, if any, classifies a user agent as an embed bot. Returns a short description
+/// of the rule so it can be shown when debugging why a user agent did or didn't match.
+pub fn matched_rule(agent: &str) -> Option<&'static str> {
+    if IMAGE_EMBED_USERAGENTS.contains(&agent) {
+        return Some("exact match in IMAGE_EMBED_USERAGENTS");
+    }
+
+    // WhatsApp useragents are weird, we just check for the word to cover all bases.
+    if agent.contains("WhatsApp/") {
+        return Some("contains WhatsApp/");
+    }
+
+    None
+}
+
+/// The user agents in [IMAGE_EMBED_USERAGENTS] with any duplicates removed, in the order they're
+/// listed.
+pub fn known_embed_agents() -> Vec<&'static str> {
+    let mut agents = Vec::with_capacity(IMAGE_EMBED_USERAGENTS.len());
+    for agent in IMAGE_EMBED_USERAGENTS {
+        if !agents.contains(&agent) {
+            agents.push(agent);
+        }
+    }
+
+    agents
+}
+
+/// Checks if a user agent belongs to a Matrix homeserver generating a link preview.
+pub fn is_matrix_agent(user_agent: &HeaderValue) -> bool {
+    user_agent
+        .to_str()
+        .map(|agent| agent.starts_with("Synapse"))
+        .unwrap_or(false)
+}
+
+/// Checks if a user agent belongs to Iframely, which can ask for a JSON response instead of HTML.
+pub fn is_iframely_agent(user_agent: &HeaderValue) -> bool {
+    user_agent
+        .to_str()
+        .map(|agent| agent.starts_with("Iframely"))
+        .unwrap_or(false)
+}
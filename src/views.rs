@@ -0,0 +1,51 @@
+//! Optional Redis backed counter tracking how many times each post's embed has been rendered.
+
+use log::warn;
+use redis::{
+    aio::ConnectionManager,
+    AsyncCommands,
+    RedisResult,
+};
+
+/// Counts how many times embeds have been rendered for each post, keyed by the post's ATUri.
+///
+/// Counting is best effort, if Redis can't be reached the failure is logged and the embed carries
+/// on without a view count rather than failing the whole request.
+#[derive(Clone)]
+pub struct ViewCounter {
+    connection: ConnectionManager,
+}
+
+impl ViewCounter {
+    pub async fn connect(url: &str) -> RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+        Ok(ViewCounter { connection })
+    }
+
+    /// Records a view of a post's embed, returning the updated count.
+    pub async fn increment(&self, aturi: &str) -> Option<u64> {
+        let mut connection = self.connection.clone();
+        connection
+            .incr(key(aturi), 1)
+            .await
+            .map_err(|err| warn!("Failed to increment view count for {aturi}: {err}"))
+            .ok()
+    }
+
+    /// Gets the current view count for a post's embed without changing it.
+    pub async fn get(&self, aturi: &str) -> Option<u64> {
+        let mut connection = self.connection.clone();
+        connection
+            .get::<_, Option<u64>>(key(aturi))
+            .await
+            .map_err(|err| warn!("Failed to get view count for {aturi}: {err}"))
+            .ok()
+            .map(Option::unwrap_or_default)
+    }
+}
+
+/// The Redis key used to store the view count for a post.
+fn key(aturi: &str) -> String {
+    format!("embed:{aturi}")
+}
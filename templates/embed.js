@@ -0,0 +1,15 @@
+(function () {
+    var script = document.currentScript;
+    var iframe = document.createElement("iframe");
+
+    iframe.src = "{{ embed_url }}";
+    iframe.title = "Bluesky post";
+    iframe.loading = "lazy";
+    iframe.style.width = "100%";
+    iframe.style.maxWidth = "550px";
+    iframe.style.height = "400px";
+    iframe.style.border = "1px solid #cfd9de";
+    iframe.style.borderRadius = "12px";
+
+    script.parentNode.insertBefore(iframe, script);
+})();